@@ -0,0 +1,135 @@
+//! Benchmarks of the wire codec against a local server, comparing unbuffered
+//! I/O on the raw socket with frames written in one call and read through a
+//! buffered reader.
+//!
+//! Run with `cargo bench --bench wire`.
+
+use std::{
+    io::{BufReader, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use cs262::wire::{self, client_handshake, Frame, Limits, Message, ServerConfig};
+
+const PORT: u16 = 15723;
+const PIPELINE_DEPTH: u32 = 100;
+
+/// How frames are written to and read from the socket.
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    /// Each field is written and read with its own system call.
+    Unbuffered,
+    /// Frames are assembled in memory and read through a buffer.
+    Buffered,
+}
+
+struct Connection {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+    mode: Mode,
+}
+
+impl Connection {
+    fn open(mode: Mode) -> Self {
+        let mut stream = TcpStream::connect(("127.0.0.1", PORT)).unwrap();
+        client_handshake(&mut stream).unwrap();
+        let reader = match mode {
+            Mode::Unbuffered => BufReader::with_capacity(1, stream.try_clone().unwrap()),
+            Mode::Buffered => {
+                stream.set_nodelay(true).unwrap();
+                BufReader::new(stream.try_clone().unwrap())
+            }
+        };
+        Self {
+            writer: stream,
+            reader,
+            mode,
+        }
+    }
+
+    fn send(&mut self, frame: &Frame) {
+        match self.mode {
+            Mode::Unbuffered => {
+                let mut body = Vec::new();
+                frame.message.encode(&mut body).unwrap();
+                self.writer.write_all(&frame.id.to_be_bytes()).unwrap();
+                self.writer
+                    .write_all(&(body.len() as u32).to_be_bytes())
+                    .unwrap();
+                frame.message.encode(&mut self.writer).unwrap();
+            }
+            Mode::Buffered => frame.encode(&mut self.writer).unwrap(),
+        }
+    }
+
+    fn recv(&mut self) -> Frame {
+        Frame::decode(&mut self.reader, &Limits::default())
+            .unwrap()
+            .unwrap()
+    }
+}
+
+fn start_server() {
+    thread::spawn(|| {
+        let config = ServerConfig {
+            port: PORT,
+            ..Default::default()
+        };
+        wire::run_server(&config).unwrap();
+    });
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", PORT)).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("server did not start");
+}
+
+fn request(id: u32) -> Frame {
+    Frame {
+        id,
+        message: Message::List("bench*".into(), "".into(), 100),
+    }
+}
+
+fn bench_wire(c: &mut Criterion) {
+    start_server();
+
+    let mut group = c.benchmark_group("latency");
+    group.sample_size(20);
+    for mode in [Mode::Unbuffered, Mode::Buffered] {
+        let mut conn = Connection::open(mode);
+        group.bench_function(BenchmarkId::from_parameter(format!("{mode:?}")), |b| {
+            b.iter(|| {
+                conn.send(&request(1));
+                conn.recv()
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("throughput");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(PIPELINE_DEPTH.into()));
+    for mode in [Mode::Unbuffered, Mode::Buffered] {
+        let mut conn = Connection::open(mode);
+        group.bench_function(BenchmarkId::from_parameter(format!("{mode:?}")), |b| {
+            b.iter(|| {
+                for id in 1..=PIPELINE_DEPTH {
+                    conn.send(&request(id));
+                }
+                for _ in 1..=PIPELINE_DEPTH {
+                    conn.recv();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_wire);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+//! Fuzz the wire decoder with arbitrary bytes, checking that it never panics
+//! and that anything it accepts survives another round trip.
+//!
+//! Run with `cargo +nightly fuzz run decode` from the repository root.
+
+#![no_main]
+
+use cs262::wire::{Frame, Limits, Message};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let limits = Limits {
+        max_frame: 4096,
+        max_field: 1024,
+    };
+
+    if let Ok(message) = Message::decode(&mut &data[..], &limits) {
+        let mut buf = Vec::new();
+        message.encode(&mut buf).unwrap();
+        let again = Message::decode(&mut &buf[..], &limits).unwrap();
+        assert_eq!(again, message);
+    }
+
+    let mut stream = data;
+    while let Ok(result) = Frame::decode(&mut stream, &limits) {
+        if let Ok(frame) = result {
+            let mut buf = Vec::new();
+            frame.encode(&mut buf).unwrap();
+            let again = Frame::decode(&mut &buf[..], &limits).unwrap().unwrap();
+            assert_eq!(again, frame);
+        }
+    }
+});
@@ -2,7 +2,9 @@
 
 #![forbid(unsafe_code)]
 
-use clap::Parser;
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Args, Parser};
 
 pub mod lamport;
 pub mod wire;
@@ -25,18 +27,140 @@ pub enum Cli {
 
 #[derive(Parser, Debug)]
 pub enum Wire {
-    Client,
-    Server,
+    Client(ClientArgs),
+    Server(ServerArgs),
+
+    /// Generate a self-signed certificate for testing TLS locally.
+    GenCert(GenCertArgs),
+
+    /// Serve an HTTP/JSON gateway in front of a chat server.
+    Gateway(GatewayArgs),
+}
+
+/// Options for running a chat client.
+#[derive(Args, Debug)]
+pub struct ClientArgs {
+    /// Local port the server is listening on.
+    #[clap(long, default_value_t = wire::WIRE_PORT)]
+    pub port: u16,
+
+    /// Connect to a Unix domain socket at this path instead of the port.
+    #[clap(long, conflicts_with = "tls_ca")]
+    pub unix: Option<PathBuf>,
+
+    /// Connect over TLS, trusting only the CA certificate in this PEM file.
+    #[clap(long)]
+    pub tls_ca: Option<PathBuf>,
+}
+
+impl ClientArgs {
+    fn config(&self) -> anyhow::Result<wire::ClientConfig> {
+        Ok(wire::ClientConfig {
+            port: self.port,
+            unix: self.unix.clone(),
+            tls: match &self.tls_ca {
+                Some(ca) => Some(wire::tls::client_config(ca)?),
+                None => None,
+            },
+            capabilities: wire::CAPABILITIES,
+        })
+    }
+}
+
+/// Options for running a chat server.
+#[derive(Args, Debug)]
+pub struct ServerArgs {
+    /// Local port to listen on.
+    #[clap(long, default_value_t = wire::WIRE_PORT)]
+    pub port: u16,
+
+    /// Listen on a Unix domain socket at this path instead of the port.
+    #[clap(long)]
+    pub unix: Option<PathBuf>,
+
+    /// Also accept WebSocket connections from browsers on this local port.
+    #[clap(long)]
+    pub websocket_port: Option<u16>,
+
+    /// Maximum size of a request frame, in bytes.
+    #[clap(long, default_value_t = wire::Limits::default().max_frame)]
+    pub max_frame_size: usize,
+
+    /// Maximum size of a single string field in a request, in bytes.
+    #[clap(long, default_value_t = wire::Limits::default().max_field)]
+    pub max_field_size: usize,
+
+    /// Space for unfinished uploads across all connections, in bytes.
+    #[clap(long, default_value_t = wire::MAX_UPLOADS)]
+    pub max_upload_space: u64,
+
+    /// Serve over TLS with the certificate chain in this PEM file.
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Private key for the TLS certificate, in a PEM file.
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    fn config(&self) -> anyhow::Result<wire::ServerConfig> {
+        Ok(wire::ServerConfig {
+            port: self.port,
+            unix: self.unix.clone(),
+            websocket_port: self.websocket_port,
+            limits: wire::Limits {
+                max_frame: self.max_frame_size,
+                max_field: self.max_field_size,
+            },
+            max_uploads: self.max_upload_space,
+            tls: match (&self.tls_cert, &self.tls_key) {
+                (Some(cert), Some(key)) => Some(wire::tls::server_config(cert, key)?),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// Options for running the HTTP gateway.
+#[derive(Args, Debug)]
+pub struct GatewayArgs {
+    /// Address to serve HTTP on.
+    #[clap(long, default_value = "127.0.0.1:8080")]
+    pub listen: SocketAddr,
+
+    /// How to connect to the chat server.
+    #[clap(flatten)]
+    pub backend: ClientArgs,
+}
+
+/// Options for generating a certificate.
+#[derive(Args, Debug)]
+pub struct GenCertArgs {
+    /// Path to write the certificate to.
+    #[clap(long, default_value = "cert.pem")]
+    pub cert: PathBuf,
+
+    /// Path to write the private key to.
+    #[clap(long, default_value = "key.pem")]
+    pub key: PathBuf,
 }
 
 impl Cli {
     pub fn run(&self) -> anyhow::Result<()> {
         match self {
-            Cli::Wire(Wire::Client) => wire::run_client(),
-            Cli::Wire(Wire::Server) => wire::run_server()?,
+            Cli::Wire(Wire::Client(args)) => wire::run_client(&args.config()?),
+            Cli::Wire(Wire::Server(args)) => wire::run_server(&args.config()?)?,
             Cli::Lamport => lamport::run(),
-            Cli::Wire2(Wire::Client) => wire2::run_client(),
-            Cli::Wire2(Wire::Server) => wire2::run_server()?,
+            Cli::Wire2(Wire::Client(args)) => wire2::run_client(&args.config()?),
+            Cli::Wire2(Wire::Server(args)) => wire2::run_server(&args.config()?)?,
+            Cli::Wire(Wire::Gateway(args)) | Cli::Wire2(Wire::Gateway(args)) => {
+                wire::gateway::run(args.listen, &args.backend.config()?)?
+            }
+            Cli::Wire(Wire::GenCert(args)) | Cli::Wire2(Wire::GenCert(args)) => {
+                wire::tls::generate_cert(&args.cert, &args.key)?;
+                println!("wrote {} and {}", args.cert.display(), args.key.display());
+            }
         }
         Ok(())
     }
@@ -4,7 +4,54 @@
 //! being sent, followed by the payload itself. All variable-length parts of the
 //! payload have a length prefixed. If the length is less than 255 bytes, then
 //! it's just encoded as a single byte. Otherwise it starts with a byte of value
-//! 0, followed by the length encoded in 4 bytes (big endian).
+//! 255, followed by the length encoded in 4 bytes (big endian). Lists are
+//! encoded the same way, as a length followed by each element in turn.
+//!
+//! Every connection starts with the client sending a [`Message::Hello`] with
+//! its [`PROTOCOL_VERSION`] and capability flags. The server replies with a
+//! [`Message::Welcome`] if it speaks the same version, or an error response
+//! before closing the connection otherwise. The encoding of these two messages
+//! must never change, so that peers of any version can negotiate.
+//!
+//! After the handshake, each message is sent in a [`Frame`] that starts with a
+//! 4-byte request identifier and the 4-byte length of the message (both big
+//! endian). The server copies the identifier of a request into its response, so
+//! clients can pipeline many requests on one connection and match up responses
+//! that arrive out of order. Frames and fields over the configured [`Limits`]
+//! are skipped and answered with an error, without buffering them.
+//!
+//! A connection can be bound to an account with [`Message::Login`], which checks
+//! the account's password and returns a session token. The token can be used
+//! to bind a later connection with [`Message::Resume`], and only the owner of
+//! an account may read its messages or delete it. Messages
+//! sent to a logged-in user are then pushed straight to their connection in a
+//! [`Message::Push`] frame with identifier 0, if the client advertised the
+//! [`CAP_PUSH`] capability. Otherwise they are queued for delivery on demand.
+//! Either way, messages stay queued until the recipient acknowledges their IDs
+//! with [`Message::Ack`], so any that are lost with a connection are delivered
+//! again after reconnecting. Account listings from [`Message::List`] show
+//! which accounts are logged in, and when the others were last seen.
+//!
+//! Files are sent as attachments, which are too large for a single frame.
+//! The sender uploads one in chunks with [`Message::Upload`] and then sends it
+//! with [`Message::SendFile`]. Recipients see its name, type and size in the
+//! [`ChatMessage`], and download the contents with [`Message::Download`]
+//! before acknowledging it.
+//!
+//! Senders can check whether each of their messages is still queued, has been
+//! delivered, or has been read (acknowledged) with [`Message::Sent`]. Clients
+//! with the [`CAP_RECEIPTS`] capability are also sent a [`Message::Receipt`]
+//! as soon as a recipient reads one of their messages. While a message is
+//! still queued, its sender can retract it with [`Message::Unsend`] or replace
+//! its text with [`Message::Edit`].
+//!
+//! The servers handle all connections on a single event loop thread, which
+//! decodes frames incrementally with a [`FrameDecoder`] and passes requests to
+//! a pool of worker threads. Connections can optionally be encrypted with TLS
+//! by the [`tls`] module, which wraps the same framing. Servers can also listen
+//! on a Unix domain socket instead of a TCP port, so that local clients can be
+//! restricted by filesystem permissions. Browsers can connect to an optional
+//! WebSocket port, where the same bytes are carried in binary messages.
 //!
 //! I'd probably use `bincode` for this in a real application, but for
 //! pedagogical reasons this exercise forbids other libraries.
@@ -12,40 +59,171 @@
 //! Run this program with `cargo run wire [client|server]`.
 
 use std::{
-    collections::{btree_map::Entry, BTreeMap},
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap},
+    fs::{self, File},
     io::{self, Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::Arc,
+    mem,
+    net::TcpListener,
+    ops::Bound,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     thread,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use colored::Colorize;
 use parking_lot::Mutex;
+use serde::Serialize;
 use wildmatch::WildMatch;
 
+pub use self::client::{Client, Pending};
+
+pub(crate) mod auth;
+mod client;
+pub mod gateway;
+pub(crate) mod server;
+pub mod tls;
+mod websocket;
+
 /// Arbitrary local port for client and server communications.
 pub const WIRE_PORT: u16 = 5722;
 
+/// Version of the wire protocol, bumped on every incompatible format change.
+pub const PROTOCOL_VERSION: u32 = 19;
+
+/// Capability flag for clients that accept server-initiated push frames.
+pub const CAP_PUSH: u32 = 1 << 0;
+
+/// Capability flag for clients that accept [`Message::Receipt`] frames when
+/// the recipient of one of their messages reads it.
+pub const CAP_RECEIPTS: u32 = 1 << 1;
+
+/// Capability flags for optional features supported by this implementation.
+pub const CAPABILITIES: u32 = CAP_PUSH | CAP_RECEIPTS;
+
+/// Largest page of results returned by [`Message::List`] or
+/// [`Message::Deliver`]. Clients that ask for at most this many can treat a
+/// shorter page as the last one.
+pub const MAX_PAGE: u32 = 1000;
+
+/// Largest chunk of an attachment accepted by [`Message::Upload`] or returned
+/// by [`Message::Download`], which fits in a field under the default limits.
+pub const MAX_CHUNK: u32 = 1 << 15;
+
+/// Largest attachment that can be uploaded, in bytes.
+pub const MAX_ATTACHMENT: u64 = 1 << 26;
+
+/// Default space for unfinished uploads across all connections to a server,
+/// in bytes.
+pub const MAX_UPLOADS: u64 = 1 << 28;
+
 /// A unified message type for client and server.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Message {
-    /// Create an account.
-    Create(String),
+    /// Create an account with a password.
+    Create(String, String),
 
-    /// List accounts, optionally by text wildcard.
-    List(String),
+    /// List accounts and whether they are online, optionally by text wildcard,
+    /// in pages of at most the given limit that start after an account name
+    /// (or at the beginning, if empty).
+    List(String, String, u32),
 
-    /// Send message to a recipient.
+    /// Send message to a recipient, from the logged-in user.
     Send(String, String),
 
-    /// Deliver undelivered messages to a particular user.
-    Deliver(String),
+    /// Deliver unacknowledged messages to the logged-in user, in pages of at
+    /// most the given limit that start after a message ID.
+    Deliver(String, u64, u32),
+
+    /// Delete the logged-in user's account, handling any queued messages
+    /// according to the mode.
+    Delete(String, DeleteMode),
+
+    /// Log in to an account with a password, binding it to this connection.
+    Login(String, String),
+
+    /// Log out of the account bound to this connection, revoking its tokens.
+    Logout,
+
+    /// Bind this connection to an existing session by its token.
+    Resume(String),
+
+    /// Acknowledge messages by their IDs, removing them from the logged-in
+    /// user's queue.
+    Ack(Vec<u64>),
+
+    /// Create a group, with the logged-in user as its first member.
+    CreateGroup(String),
+
+    /// Add the logged-in user to a group.
+    JoinGroup(String),
+
+    /// Remove the logged-in user from a group, which is deleted once empty.
+    LeaveGroup(String),
+
+    /// List the members of a group.
+    Members(String),
+
+    /// Send message to every other member of a group, from the logged-in user.
+    SendGroup(String, String),
+
+    /// Send message to every other account matching a text wildcard, from the
+    /// logged-in user, returning the accounts it was sent to.
+    Broadcast(String, String),
+
+    /// List the status of messages sent by the logged-in user, in pages of at
+    /// most the given limit that start after a message ID.
+    Sent(u64, u32),
+
+    /// Refuse messages from an account to the logged-in user.
+    Block(String),
+
+    /// Accept messages from a blocked account again.
+    Unblock(String),
+
+    /// Retract a message sent by the logged-in user that is still queued.
+    Unsend(u64),
+
+    /// Replace the text of a message sent by the logged-in user that is still
+    /// queued.
+    Edit(u64, String),
 
-    /// Delete an account (fails if it has queued messages).
-    Delete(String),
+    /// Rename the logged-in user's account, along with its messages, groups
+    /// and block lists.
+    Rename(String, String),
+
+    /// Store a chunk of an attachment for this connection, at an offset that
+    /// must follow the previous chunk. A chunk at offset zero starts a new
+    /// attachment.
+    Upload(u64, Vec<u8>),
+
+    /// Send the uploaded attachment to a recipient with a file name and MIME
+    /// type, from the logged-in user.
+    SendFile(String, String, String),
+
+    /// Read at most the given number of bytes from the attachment of a message
+    /// queued for the logged-in user, starting at an offset.
+    Download(u64, u64, u32),
 
     /// Returned by the server.
-    Response(Result<String, String>),
+    Response(Result<Reply, String>),
+
+    /// Message delivered immediately to a logged-in user by the server.
+    Push(ChatMessage),
+
+    /// Notice to the sender of a message that its recipient has read it.
+    Receipt(Receipt),
+
+    /// Handshake from the client, with its protocol version and capabilities.
+    Hello(u32, u32),
+
+    /// Handshake reply from the server, with its protocol version and the
+    /// capabilities supported by both sides.
+    Welcome(u32, u32),
 }
 
 impl Message {
@@ -64,6 +242,43 @@ impl Message {
         stream.write_all(s.as_bytes())
     }
 
+    fn encode_u32(stream: &mut impl Write, n: u32) -> io::Result<()> {
+        stream.write_all(&n.to_be_bytes())
+    }
+
+    fn encode_u64(stream: &mut impl Write, n: u64) -> io::Result<()> {
+        stream.write_all(&n.to_be_bytes())
+    }
+
+    fn encode_list(stream: &mut impl Write, list: &[String]) -> io::Result<()> {
+        Self::encode_len(stream, list.len())?;
+        for s in list {
+            Self::encode_str(stream, s)?;
+        }
+        Ok(())
+    }
+
+    fn encode_bytes(stream: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+        Self::encode_len(stream, bytes.len())?;
+        stream.write_all(bytes)
+    }
+
+    fn encode_ids(stream: &mut impl Write, ids: &[u64]) -> io::Result<()> {
+        Self::encode_len(stream, ids.len())?;
+        for &id in ids {
+            Self::encode_u64(stream, id)?;
+        }
+        Ok(())
+    }
+
+    fn encode_messages(stream: &mut impl Write, messages: &[ChatMessage]) -> io::Result<()> {
+        Self::encode_len(stream, messages.len())?;
+        for message in messages {
+            message.encode(stream)?;
+        }
+        Ok(())
+    }
+
     fn decode_len(stream: &mut impl Read) -> io::Result<usize> {
         let mut buf = [0; 4];
         stream.read_exact(&mut buf[..1])?;
@@ -76,65 +291,359 @@ impl Message {
         Ok(len)
     }
 
-    fn decode_str(stream: &mut impl Read) -> io::Result<String> {
+    fn decode_bytes(stream: &mut impl Read, limits: &Limits) -> io::Result<Vec<u8>> {
         let len = Self::decode_len(stream)?;
+        if len > limits.max_field {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "field of {len} bytes exceeds limit of {} bytes",
+                    limits.max_field
+                ),
+            ));
+        }
         let mut buf = vec![0; len];
         stream.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn decode_str(stream: &mut impl Read, limits: &Limits) -> io::Result<String> {
+        let buf = Self::decode_bytes(stream, limits)?;
         String::from_utf8(buf).map_err(|_| {
             io::Error::new(io::ErrorKind::InvalidData, "wire message had invalid UTF-8")
         })
     }
 
+    fn decode_u32(stream: &mut impl Read) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn decode_u64(stream: &mut impl Read) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        stream.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn decode_ids(stream: &mut impl Read) -> io::Result<Vec<u64>> {
+        let len = Self::decode_len(stream)?;
+        let mut ids = Vec::new();
+        for _ in 0..len {
+            ids.push(Self::decode_u64(stream)?);
+        }
+        Ok(ids)
+    }
+
+    fn decode_messages(stream: &mut impl Read, limits: &Limits) -> io::Result<Vec<ChatMessage>> {
+        let len = Self::decode_len(stream)?;
+        let mut messages = Vec::new();
+        for _ in 0..len {
+            messages.push(ChatMessage::decode(stream, limits)?);
+        }
+        Ok(messages)
+    }
+
+    fn decode_list(stream: &mut impl Read, limits: &Limits) -> io::Result<Vec<String>> {
+        let len = Self::decode_len(stream)?;
+        let mut list = Vec::new();
+        for _ in 0..len {
+            list.push(Self::decode_str(stream, limits)?);
+        }
+        Ok(list)
+    }
+
     /// Encode a message onto a writable stream.
     pub fn encode(&self, stream: &mut impl Write) -> io::Result<()> {
         match self {
-            Message::Create(name) => {
+            Message::Create(name, password) => {
                 stream.write_all(&[1])?;
-                Self::encode_str(stream, name)
+                Self::encode_str(stream, name)?;
+                Self::encode_str(stream, password)
             }
-            Message::List(filter) => {
+            Message::List(filter, after, limit) => {
                 stream.write_all(&[2])?;
-                Self::encode_str(stream, filter)
+                Self::encode_str(stream, filter)?;
+                Self::encode_str(stream, after)?;
+                Self::encode_u32(stream, *limit)
             }
             Message::Send(name, text) => {
                 stream.write_all(&[3])?;
                 Self::encode_str(stream, name)?;
                 Self::encode_str(stream, text)
             }
-            Message::Deliver(name) => {
+            Message::Deliver(name, after, limit) => {
                 stream.write_all(&[4])?;
-                Self::encode_str(stream, name)
+                Self::encode_str(stream, name)?;
+                Self::encode_u64(stream, *after)?;
+                Self::encode_u32(stream, *limit)
             }
-            Message::Delete(name) => {
+            Message::Delete(name, mode) => {
                 stream.write_all(&[5])?;
+                Self::encode_str(stream, name)?;
+                stream.write_all(&[*mode as u8])
+            }
+            Message::Login(name, password) => {
+                stream.write_all(&[6])?;
+                Self::encode_str(stream, name)?;
+                Self::encode_str(stream, password)
+            }
+            Message::Logout => stream.write_all(&[7]),
+            Message::Resume(token) => {
+                stream.write_all(&[8])?;
+                Self::encode_str(stream, token)
+            }
+            Message::Ack(ids) => {
+                stream.write_all(&[9])?;
+                Self::encode_ids(stream, ids)
+            }
+            Message::CreateGroup(group) => {
+                stream.write_all(&[10])?;
+                Self::encode_str(stream, group)
+            }
+            Message::JoinGroup(group) => {
+                stream.write_all(&[11])?;
+                Self::encode_str(stream, group)
+            }
+            Message::LeaveGroup(group) => {
+                stream.write_all(&[12])?;
+                Self::encode_str(stream, group)
+            }
+            Message::Members(group) => {
+                stream.write_all(&[13])?;
+                Self::encode_str(stream, group)
+            }
+            Message::SendGroup(group, text) => {
+                stream.write_all(&[14])?;
+                Self::encode_str(stream, group)?;
+                Self::encode_str(stream, text)
+            }
+            Message::Broadcast(pattern, text) => {
+                stream.write_all(&[15])?;
+                Self::encode_str(stream, pattern)?;
+                Self::encode_str(stream, text)
+            }
+            Message::Sent(after, limit) => {
+                stream.write_all(&[16])?;
+                Self::encode_u64(stream, *after)?;
+                Self::encode_u32(stream, *limit)
+            }
+            Message::Block(name) => {
+                stream.write_all(&[17])?;
                 Self::encode_str(stream, name)
             }
-            Message::Response(Ok(resp)) => {
-                stream.write_all(&[242])?;
-                Self::encode_str(stream, resp)
+            Message::Unblock(name) => {
+                stream.write_all(&[18])?;
+                Self::encode_str(stream, name)
+            }
+            Message::Unsend(id) => {
+                stream.write_all(&[19])?;
+                Self::encode_u64(stream, *id)
+            }
+            Message::Edit(id, text) => {
+                stream.write_all(&[20])?;
+                Self::encode_u64(stream, *id)?;
+                Self::encode_str(stream, text)
+            }
+            Message::Rename(name, new_name) => {
+                stream.write_all(&[21])?;
+                Self::encode_str(stream, name)?;
+                Self::encode_str(stream, new_name)
+            }
+            Message::Upload(offset, data) => {
+                stream.write_all(&[22])?;
+                Self::encode_u64(stream, *offset)?;
+                Self::encode_bytes(stream, data)
+            }
+            Message::SendFile(name, filename, mime) => {
+                stream.write_all(&[23])?;
+                Self::encode_str(stream, name)?;
+                Self::encode_str(stream, filename)?;
+                Self::encode_str(stream, mime)
+            }
+            Message::Download(id, offset, limit) => {
+                stream.write_all(&[24])?;
+                Self::encode_u64(stream, *id)?;
+                Self::encode_u64(stream, *offset)?;
+                Self::encode_u32(stream, *limit)
             }
+            Message::Response(Ok(Reply::Ack)) => stream.write_all(&[242]),
             Message::Response(Err(err)) => {
                 stream.write_all(&[243])?;
                 Self::encode_str(stream, err)
             }
+            Message::Push(message) => {
+                stream.write_all(&[244])?;
+                message.encode(stream)
+            }
+            Message::Response(Ok(Reply::Accounts(names))) => {
+                stream.write_all(&[245])?;
+                Self::encode_list(stream, names)
+            }
+            Message::Response(Ok(Reply::Messages(messages))) => {
+                stream.write_all(&[246])?;
+                Self::encode_messages(stream, messages)
+            }
+            Message::Response(Ok(Reply::Token(token))) => {
+                stream.write_all(&[247])?;
+                Self::encode_str(stream, token)
+            }
+            Message::Response(Ok(Reply::Receipts(receipts))) => {
+                stream.write_all(&[248])?;
+                Self::encode_len(stream, receipts.len())?;
+                for receipt in receipts {
+                    receipt.encode(stream)?;
+                }
+                Ok(())
+            }
+            Message::Response(Ok(Reply::Presence(accounts))) => {
+                stream.write_all(&[250])?;
+                Self::encode_len(stream, accounts.len())?;
+                for presence in accounts {
+                    presence.encode(stream)?;
+                }
+                Ok(())
+            }
+            Message::Response(Ok(Reply::Data(data))) => {
+                stream.write_all(&[251])?;
+                Self::encode_bytes(stream, data)
+            }
+            Message::Receipt(receipt) => {
+                stream.write_all(&[249])?;
+                receipt.encode(stream)
+            }
+            Message::Hello(version, capabilities) => {
+                stream.write_all(&[240])?;
+                Self::encode_u32(stream, *version)?;
+                Self::encode_u32(stream, *capabilities)
+            }
+            Message::Welcome(version, capabilities) => {
+                stream.write_all(&[241])?;
+                Self::encode_u32(stream, *version)?;
+                Self::encode_u32(stream, *capabilities)
+            }
         }
     }
 
     /// Decode the next message from a readable stream.
-    pub fn decode(stream: &mut impl Read) -> io::Result<Self> {
+    ///
+    /// Decoding fails with [`io::ErrorKind::InvalidData`] if any field is
+    /// longer than the limit. Lists have no limit on their number of elements,
+    /// so the caller should bound the input by the size of the frame.
+    pub fn decode(stream: &mut impl Read, limits: &Limits) -> io::Result<Self> {
         let mut buf = [0];
         stream.read_exact(&mut buf)?;
         match buf[0] {
-            1 => Ok(Message::Create(Self::decode_str(stream)?)),
-            2 => Ok(Message::List(Self::decode_str(stream)?)),
+            1 => Ok(Message::Create(
+                Self::decode_str(stream, limits)?,
+                Self::decode_str(stream, limits)?,
+            )),
+            2 => Ok(Message::List(
+                Self::decode_str(stream, limits)?,
+                Self::decode_str(stream, limits)?,
+                Self::decode_u32(stream)?,
+            )),
             3 => Ok(Message::Send(
-                Self::decode_str(stream)?,
-                Self::decode_str(stream)?,
+                Self::decode_str(stream, limits)?,
+                Self::decode_str(stream, limits)?,
+            )),
+            4 => Ok(Message::Deliver(
+                Self::decode_str(stream, limits)?,
+                Self::decode_u64(stream)?,
+                Self::decode_u32(stream)?,
+            )),
+            5 => Ok(Message::Delete(
+                Self::decode_str(stream, limits)?,
+                DeleteMode::decode(stream)?,
+            )),
+            6 => Ok(Message::Login(
+                Self::decode_str(stream, limits)?,
+                Self::decode_str(stream, limits)?,
+            )),
+            7 => Ok(Message::Logout),
+            8 => Ok(Message::Resume(Self::decode_str(stream, limits)?)),
+            9 => Ok(Message::Ack(Self::decode_ids(stream)?)),
+            10 => Ok(Message::CreateGroup(Self::decode_str(stream, limits)?)),
+            11 => Ok(Message::JoinGroup(Self::decode_str(stream, limits)?)),
+            12 => Ok(Message::LeaveGroup(Self::decode_str(stream, limits)?)),
+            13 => Ok(Message::Members(Self::decode_str(stream, limits)?)),
+            14 => Ok(Message::SendGroup(
+                Self::decode_str(stream, limits)?,
+                Self::decode_str(stream, limits)?,
+            )),
+            15 => Ok(Message::Broadcast(
+                Self::decode_str(stream, limits)?,
+                Self::decode_str(stream, limits)?,
+            )),
+            16 => Ok(Message::Sent(
+                Self::decode_u64(stream)?,
+                Self::decode_u32(stream)?,
+            )),
+            17 => Ok(Message::Block(Self::decode_str(stream, limits)?)),
+            18 => Ok(Message::Unblock(Self::decode_str(stream, limits)?)),
+            19 => Ok(Message::Unsend(Self::decode_u64(stream)?)),
+            20 => Ok(Message::Edit(
+                Self::decode_u64(stream)?,
+                Self::decode_str(stream, limits)?,
+            )),
+            21 => Ok(Message::Rename(
+                Self::decode_str(stream, limits)?,
+                Self::decode_str(stream, limits)?,
             )),
-            4 => Ok(Message::Deliver(Self::decode_str(stream)?)),
-            5 => Ok(Message::Delete(Self::decode_str(stream)?)),
-            242 => Ok(Message::Response(Ok(Self::decode_str(stream)?))),
-            243 => Ok(Message::Response(Err(Self::decode_str(stream)?))),
+            22 => Ok(Message::Upload(
+                Self::decode_u64(stream)?,
+                Self::decode_bytes(stream, limits)?,
+            )),
+            23 => Ok(Message::SendFile(
+                Self::decode_str(stream, limits)?,
+                Self::decode_str(stream, limits)?,
+                Self::decode_str(stream, limits)?,
+            )),
+            24 => Ok(Message::Download(
+                Self::decode_u64(stream)?,
+                Self::decode_u64(stream)?,
+                Self::decode_u32(stream)?,
+            )),
+            240 => Ok(Message::Hello(
+                Self::decode_u32(stream)?,
+                Self::decode_u32(stream)?,
+            )),
+            241 => Ok(Message::Welcome(
+                Self::decode_u32(stream)?,
+                Self::decode_u32(stream)?,
+            )),
+            242 => Ok(Message::Response(Ok(Reply::Ack))),
+            243 => Ok(Message::Response(Err(Self::decode_str(stream, limits)?))),
+            244 => Ok(Message::Push(ChatMessage::decode(stream, limits)?)),
+            245 => Ok(Message::Response(Ok(Reply::Accounts(Self::decode_list(
+                stream, limits,
+            )?)))),
+            246 => Ok(Message::Response(Ok(Reply::Messages(
+                Self::decode_messages(stream, limits)?,
+            )))),
+            247 => Ok(Message::Response(Ok(Reply::Token(Self::decode_str(
+                stream, limits,
+            )?)))),
+            248 => {
+                let len = Self::decode_len(stream)?;
+                let mut receipts = Vec::new();
+                for _ in 0..len {
+                    receipts.push(Receipt::decode(stream, limits)?);
+                }
+                Ok(Message::Response(Ok(Reply::Receipts(receipts))))
+            }
+            249 => Ok(Message::Receipt(Receipt::decode(stream, limits)?)),
+            250 => {
+                let len = Self::decode_len(stream)?;
+                let mut accounts = Vec::new();
+                for _ in 0..len {
+                    accounts.push(Presence::decode(stream, limits)?);
+                }
+                Ok(Message::Response(Ok(Reply::Presence(accounts))))
+            }
+            251 => Ok(Message::Response(Ok(Reply::Data(Self::decode_bytes(
+                stream, limits,
+            )?)))),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "wire message had invalid type",
@@ -143,169 +652,1564 @@ impl Message {
     }
 }
 
-fn run_client_once() -> io::Result<()> {
-    let mut stream = TcpStream::connect(("127.0.0.1", WIRE_PORT))?;
+/// Payload of a successful response from the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// The request succeeded with nothing to return.
+    Ack,
 
-    // This was also mostly written by Copilot.
-    loop {
-        let mut line = String::new();
-        eprint!("{}", "wire> ".green());
-        io::stdin().read_line(&mut line)?;
-        let mut words = line.split_whitespace();
-        let Some(cmd) = words.next() else { break };
-        match cmd {
-            "create" => {
-                let Some(name) = words.next() else {
-                    eprintln!("missing argument");
-                    continue;
-                };
-                Message::Create(name.into()).encode(&mut stream)?;
-            }
-            "list" => {
-                let filter = words.next().unwrap_or("");
-                Message::List(filter.into()).encode(&mut stream)?;
-            }
-            "send" => {
-                let Some(name) = words.next() else {
-                    eprintln!("missing argument");
-                    continue;
-                };
-                let text = words.collect::<Vec<_>>().join(" ");
-                Message::Send(name.into(), text).encode(&mut stream)?;
-            }
-            "deliver" => {
-                let Some(name) = words.next() else {
-                    eprintln!("missing argument");
-                    continue;
-                };
-                Message::Deliver(name.into()).encode(&mut stream)?;
-            }
-            "delete" => {
-                let Some(name) = words.next() else {
-                    eprintln!("missing argument");
-                    continue;
-                };
-                Message::Delete(name.into()).encode(&mut stream)?;
+    /// Names of accounts, in sorted order.
+    Accounts(Vec<String>),
+
+    /// Messages delivered to a user, oldest first.
+    Messages(Vec<ChatMessage>),
+
+    /// Token for a new session.
+    Token(String),
+
+    /// Status of messages sent by a user, oldest first.
+    Receipts(Vec<Receipt>),
+
+    /// Accounts with whether they are online, in sorted order.
+    Presence(Vec<Presence>),
+
+    /// A chunk of an attachment.
+    Data(Vec<u8>),
+}
+
+/// A chat message with its metadata, as stored by the server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ChatMessage {
+    /// Unique identifier assigned by the server.
+    pub id: u64,
+
+    /// Account that sent the message.
+    pub sender: String,
+
+    /// Group the message was sent to, or `None` if it was sent directly.
+    pub group: Option<String>,
+
+    /// Time the server received the message, in milliseconds since the epoch.
+    pub timestamp: u64,
+
+    /// Contents of the message.
+    pub text: String,
+
+    /// File attached to the message, if any.
+    pub attachment: Option<Attachment>,
+}
+
+impl ChatMessage {
+    fn encode(&self, stream: &mut impl Write) -> io::Result<()> {
+        Message::encode_u64(stream, self.id)?;
+        Message::encode_str(stream, &self.sender)?;
+        // Group names are never empty, so an empty string means no group.
+        Message::encode_str(stream, self.group.as_deref().unwrap_or_default())?;
+        Message::encode_u64(stream, self.timestamp)?;
+        Message::encode_str(stream, &self.text)?;
+        match &self.attachment {
+            Some(attachment) => {
+                stream.write_all(&[1])?;
+                attachment.encode(stream)
             }
+            None => stream.write_all(&[0]),
+        }
+    }
+
+    fn decode(stream: &mut impl Read, limits: &Limits) -> io::Result<Self> {
+        let id = Message::decode_u64(stream)?;
+        let sender = Message::decode_str(stream, limits)?;
+        let group = Some(Message::decode_str(stream, limits)?).filter(|g| !g.is_empty());
+        let timestamp = Message::decode_u64(stream)?;
+        let text = Message::decode_str(stream, limits)?;
+        let mut buf = [0];
+        stream.read_exact(&mut buf)?;
+        let attachment = match buf[0] {
+            0 => None,
+            1 => Some(Attachment::decode(stream, limits)?),
             _ => {
-                eprintln!("unknown command");
-                continue;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "chat message had invalid attachment flag",
+                ))
             }
-        }
+        };
+        Ok(Self {
+            id,
+            sender,
+            group,
+            timestamp,
+            text,
+            attachment,
+        })
+    }
+}
 
-        match Message::decode(&mut stream)? {
-            Message::Response(Ok(resp)) => print!("{}", resp.yellow()),
-            Message::Response(Err(err)) => eprintln!("{} {}", "error:".red(), err),
-            _ => eprintln!("unexpected response"),
-        }
+/// Description of a file attached to a message, whose contents are downloaded
+/// separately.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Attachment {
+    /// Name of the file, as given by the sender.
+    pub filename: String,
+
+    /// MIME type of the file, as given by the sender.
+    pub mime: String,
+
+    /// Size of the file, in bytes.
+    pub size: u64,
+}
+
+impl Attachment {
+    fn encode(&self, stream: &mut impl Write) -> io::Result<()> {
+        Message::encode_str(stream, &self.filename)?;
+        Message::encode_str(stream, &self.mime)?;
+        Message::encode_u64(stream, self.size)
     }
 
-    Ok(())
+    fn decode(stream: &mut impl Read, limits: &Limits) -> io::Result<Self> {
+        Ok(Self {
+            filename: Message::decode_str(stream, limits)?,
+            mime: Message::decode_str(stream, limits)?,
+            size: Message::decode_u64(stream)?,
+        })
+    }
 }
 
-pub fn run_client() {
-    loop {
-        if let Err(err) = run_client_once() {
-            eprintln!("{}", format!("I/O error: {err}").magenta());
-            thread::sleep(Duration::from_millis(250));
+/// What happens to the queued messages of an account when it is deleted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeleteMode {
+    /// Refuse to delete the account while it has queued messages.
+    #[default]
+    Restrict = 0,
+
+    /// Discard any queued messages.
+    Purge = 1,
+
+    /// Return queued messages to the queues of their senders, unchanged.
+    Bounce = 2,
+
+    /// Discard any queued messages, and reserve the name so that it can never
+    /// be used by a new account.
+    Tombstone = 3,
+}
+
+impl DeleteMode {
+    fn decode(stream: &mut impl Read) -> io::Result<Self> {
+        let mut buf = [0];
+        stream.read_exact(&mut buf)?;
+        match buf[0] {
+            0 => Ok(DeleteMode::Restrict),
+            1 => Ok(DeleteMode::Purge),
+            2 => Ok(DeleteMode::Bounce),
+            3 => Ok(DeleteMode::Tombstone),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "delete had invalid mode",
+            )),
         }
     }
 }
 
-pub fn run_server() -> io::Result<()> {
-    let listener = TcpListener::bind(("127.0.0.1", WIRE_PORT))?;
+impl FromStr for DeleteMode {
+    type Err = String;
 
-    // All state for the server is in this threadsafe map.
-    let accounts: Arc<Mutex<BTreeMap<String, Vec<String>>>> = Default::default();
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "restrict" => Ok(DeleteMode::Restrict),
+            "purge" => Ok(DeleteMode::Purge),
+            "bounce" => Ok(DeleteMode::Bounce),
+            "tombstone" => Ok(DeleteMode::Tombstone),
+            _ => Err(format!("invalid delete mode: {s}")),
+        }
+    }
+}
 
-    for stream in listener.incoming() {
-        let mut stream = match stream {
-            Ok(stream) => stream,
-            Err(err) => {
-                eprintln!("error accepting connection: {}", err);
-                continue;
-            }
-        };
+/// Progress of a message towards its recipient.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageState {
+    /// Waiting in the recipient's queue.
+    Queued = 0,
 
-        let accounts = Arc::clone(&accounts);
-
-        thread::spawn::<_, io::Result<()>>(move || loop {
-            // Most of this part was written by Copilot.
-            let resp = match Message::decode(&mut stream)? {
-                Message::Create(name) => {
-                    eprintln!("create account {name}");
-                    let mut accounts = accounts.lock();
-                    if accounts.contains_key(&name) {
-                        Err("account already exists".into())
-                    } else {
-                        accounts.insert(name.clone(), Vec::new());
-                        Ok("".into())
-                    }
-                }
-                Message::List(filter) => {
-                    let matcher = if filter.is_empty() {
-                        WildMatch::new("*")
-                    } else {
-                        WildMatch::new(&filter)
-                    };
+    /// Pushed or delivered to the recipient, but not yet acknowledged.
+    Delivered = 1,
 
-                    let mut results = String::new();
-                    let accounts = accounts.lock();
-                    for key in accounts.keys() {
-                        if matcher.matches(key) {
-                            results += key;
-                            results += "\n";
-                        }
-                    }
-                    Ok(results)
-                }
-                Message::Send(name, text) => {
-                    eprintln!("send message to {name}");
-                    let mut accounts = accounts.lock();
-                    if let Some(queue) = accounts.get_mut(&name) {
-                        queue.push(text.clone());
-                        Ok("".into())
-                    } else {
-                        Err("account does not exist".into())
-                    }
-                }
-                Message::Deliver(name) => {
-                    eprintln!("deliver messages to {name}");
-                    let mut accounts = accounts.lock();
-                    if let Some(queue) = accounts.get_mut(&name) {
-                        let mut results = String::new();
-                        for msg in queue.drain(..) {
-                            results += &msg;
-                            results += "\n";
-                        }
-                        Ok(results)
-                    } else {
-                        Err("account does not exist".into())
-                    }
-                }
-                Message::Delete(name) => {
-                    eprintln!("delete account {name}");
-                    let mut accounts = accounts.lock();
-                    match accounts.entry(name) {
-                        Entry::Occupied(entry) => {
-                            if entry.get().is_empty() {
-                                entry.remove();
-                                Ok("".into())
-                            } else {
-                                Err("account has messages".into())
-                            }
-                        }
-                        Entry::Vacant(_) => Err("account does not exist".into()),
-                    }
-                }
-                _ => {
-                    eprintln!("unexpected message from client");
-                    continue;
-                }
-            };
-            Message::Response(resp).encode(&mut stream)?;
-        });
+    /// Acknowledged by the recipient.
+    Read = 2,
+
+    /// Returned to the sender's own queue, because the recipient deleted their
+    /// account.
+    Bounced = 3,
+}
+
+/// Delivery status of a message, as seen by its sender.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Receipt {
+    /// Identifier of the message.
+    pub id: u64,
+
+    /// Account the message was sent to.
+    pub recipient: String,
+
+    /// How far the message has got.
+    pub state: MessageState,
+}
+
+impl Receipt {
+    fn encode(&self, stream: &mut impl Write) -> io::Result<()> {
+        Message::encode_u64(stream, self.id)?;
+        Message::encode_str(stream, &self.recipient)?;
+        stream.write_all(&[self.state as u8])
     }
 
-    Ok(())
+    fn decode(stream: &mut impl Read, limits: &Limits) -> io::Result<Self> {
+        let id = Message::decode_u64(stream)?;
+        let recipient = Message::decode_str(stream, limits)?;
+        let mut buf = [0];
+        stream.read_exact(&mut buf)?;
+        let state = match buf[0] {
+            0 => MessageState::Queued,
+            1 => MessageState::Delivered,
+            2 => MessageState::Read,
+            3 => MessageState::Bounced,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "receipt had invalid state",
+                ))
+            }
+        };
+        Ok(Self {
+            id,
+            recipient,
+            state,
+        })
+    }
+}
+
+/// Whether an account is connected to any server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Presence {
+    /// Name of the account.
+    pub name: String,
+
+    /// Whether the account is logged in on at least one connection.
+    pub online: bool,
+
+    /// When the account was last connected, in milliseconds since the epoch,
+    /// or zero if it never has been.
+    pub last_seen: u64,
+}
+
+impl Presence {
+    fn encode(&self, stream: &mut impl Write) -> io::Result<()> {
+        Message::encode_str(stream, &self.name)?;
+        stream.write_all(&[self.online as u8])?;
+        Message::encode_u64(stream, self.last_seen)
+    }
+
+    fn decode(stream: &mut impl Read, limits: &Limits) -> io::Result<Self> {
+        let name = Message::decode_str(stream, limits)?;
+        let mut buf = [0];
+        stream.read_exact(&mut buf)?;
+        let online = match buf[0] {
+            0 => false,
+            1 => true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "presence had invalid online flag",
+                ))
+            }
+        };
+        Ok(Self {
+            name,
+            online,
+            last_seen: Message::decode_u64(stream)?,
+        })
+    }
+}
+
+/// Current time in milliseconds since the epoch, for message timestamps.
+pub(crate) fn timestamp() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    now.map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Limits on the size of decoded data, to bound memory use per connection.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum size of a frame, in bytes.
+    pub max_frame: usize,
+
+    /// Maximum size of a single string field, in bytes.
+    pub max_field: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame: 1 << 20,
+            max_field: 1 << 16,
+        }
+    }
+}
+
+impl Limits {
+    fn check_frame(&self, len: u64) -> Result<(), String> {
+        if len > self.max_frame as u64 {
+            Err(format!(
+                "frame of {len} bytes exceeds limit of {} bytes",
+                self.max_frame
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A frame that could not be decoded, but was skipped over in the stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RejectedFrame {
+    /// Identifier of the frame.
+    pub id: u32,
+
+    /// Why the frame was rejected, suitable for an error response.
+    pub reason: String,
+}
+
+/// A message tagged with a request identifier.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Identifier chosen by the client and echoed back in the response.
+    pub id: u32,
+
+    /// The message being sent.
+    pub message: Message,
+}
+
+impl Frame {
+    /// Encode a frame onto a writable stream.
+    ///
+    /// The frame is assembled in memory first and written with a single call,
+    /// so it isn't split into many small segments on a socket.
+    pub fn encode(&self, stream: &mut impl Write) -> io::Result<()> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        stream.write_all(&buf)
+    }
+
+    /// Encode a frame, appending it to the end of a buffer.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend([0; 8]);
+        self.message
+            .encode(buf)
+            .expect("writing to a vec should not fail");
+        let len = u32::try_from(buf.len() - start - 8).expect("frame too long");
+        buf[start..start + 4].copy_from_slice(&self.id.to_be_bytes());
+        buf[start + 4..start + 8].copy_from_slice(&len.to_be_bytes());
+    }
+
+    /// Decode the next frame from a readable stream.
+    ///
+    /// Frames that are too large or malformed are skipped and returned as a
+    /// [`RejectedFrame`], so decoding can continue with the next frame. Only
+    /// I/O errors on the stream itself are returned as errors.
+    ///
+    /// Decoding makes many small reads, so sockets should be wrapped in an
+    /// [`io::BufReader`] first.
+    pub fn decode(
+        stream: &mut impl Read,
+        limits: &Limits,
+    ) -> io::Result<Result<Self, RejectedFrame>> {
+        let id = Message::decode_u32(stream)?;
+        let len = Message::decode_u32(stream)? as u64;
+        let mut body = stream.take(len);
+        let result = if let Err(err) = limits.check_frame(len) {
+            Err(err)
+        } else {
+            match Message::decode(&mut body, limits) {
+                Ok(_) if body.limit() > 0 => Err("frame had trailing bytes".into()),
+                Ok(message) => Ok(Self { id, message }),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    Err("frame was truncated".into())
+                }
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => Err(err.to_string()),
+                Err(err) => return Err(err),
+            }
+        };
+
+        // Skip the rest of the frame without buffering it.
+        io::copy(&mut body, &mut io::sink())?;
+        if body.limit() > 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(result.map_err(|reason| RejectedFrame { id, reason }))
+    }
+}
+
+/// Incremental decoder for frames that arrive in arbitrary chunks, such as from
+/// a non-blocking socket.
+///
+/// At most one frame is buffered at a time. Bytes of frames that are over the
+/// size limit are discarded as they arrive.
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    skip: usize,
+    limits: Limits,
+}
+
+impl FrameDecoder {
+    /// Create a new decoder with the given limits.
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            buf: Vec::new(),
+            skip: 0,
+            limits,
+        }
+    }
+
+    /// Add bytes received from the stream.
+    pub fn extend(&mut self, data: &[u8]) {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        self.buf.extend_from_slice(&data[skipped..]);
+    }
+
+    /// Decode the next frame, if all of it has been received.
+    pub fn next_frame(&mut self) -> Option<Result<Frame, RejectedFrame>> {
+        let header = self.buf.get(..8)?;
+        let id = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let len = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+        if let Err(reason) = self.limits.check_frame(len as u64) {
+            let buffered = self.buf.len().min(8 + len);
+            self.buf.drain(..buffered);
+            self.skip = 8 + len - buffered;
+            return Some(Err(RejectedFrame { id, reason }));
+        }
+        if self.buf.len() < 8 + len {
+            return None;
+        }
+        let result = Frame::decode(&mut &self.buf[..8 + len], &self.limits)
+            .expect("decoding a complete frame should not fail");
+        self.buf.drain(..8 + len);
+        Some(result)
+    }
+}
+
+/// Length of an encoded [`Message::Hello`], which never changes.
+pub(crate) const HELLO_LEN: usize = 9;
+
+/// Decode a handshake message, which is not sent in a frame.
+fn decode_handshake(stream: &mut impl Read) -> io::Result<Message> {
+    let limits = Limits::default();
+    Message::decode(&mut stream.take(limits.max_field as u64 + 16), &limits)
+}
+
+/// Perform the client side of the handshake, returning shared capabilities.
+pub fn client_handshake(stream: &mut (impl Read + Write)) -> io::Result<u32> {
+    client_handshake_with(stream, CAPABILITIES)
+}
+
+/// Perform the client side of the handshake, advertising only some of the
+/// supported capabilities.
+pub(crate) fn client_handshake_with(
+    stream: &mut (impl Read + Write),
+    capabilities: u32,
+) -> io::Result<u32> {
+    let mut buf = Vec::with_capacity(HELLO_LEN);
+    Message::Hello(PROTOCOL_VERSION, capabilities).encode(&mut buf)?;
+    stream.write_all(&buf)?;
+    match decode_handshake(stream)? {
+        Message::Welcome(PROTOCOL_VERSION, capabilities) => Ok(capabilities),
+        Message::Welcome(version, _) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("server speaks protocol version {version}, expected {PROTOCOL_VERSION}"),
+        )),
+        Message::Response(Err(err)) => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("server rejected handshake: {err}"),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected handshake response",
+        )),
+    }
+}
+
+/// Check the handshake bytes received from a client so far, returning the
+/// shared capabilities, or `None` if more bytes are needed.
+///
+/// Incompatible clients should be sent the error in a response, and then the
+/// connection should be closed.
+pub(crate) fn accept_hello(hello: &[u8]) -> Option<Result<u32, String>> {
+    if hello.first()? == &240 && hello.len() < HELLO_LEN {
+        return None;
+    }
+    Some(match Message::decode(&mut &hello[..], &Limits::default()) {
+        Ok(Message::Hello(PROTOCOL_VERSION, capabilities)) => Ok(capabilities & CAPABILITIES),
+        Ok(Message::Hello(version, _)) => Err(format!(
+            "unsupported protocol version {version}, server speaks version {PROTOCOL_VERSION}"
+        )),
+        _ => Err("expected hello message, client may be outdated".into()),
+    })
+}
+
+fn print_message(message: &ChatMessage) {
+    let time = UNIX_EPOCH + Duration::from_millis(message.timestamp);
+    let group = match &message.group {
+        Some(group) => format!(" in {group}"),
+        None => String::new(),
+    };
+    let attachment = match &message.attachment {
+        Some(a) => format!(" with {} ({}, {} bytes)", a.filename, a.mime, a.size),
+        None => String::new(),
+    };
+    let header = format!(
+        "#{} from {}{} at {}{}",
+        message.id,
+        message.sender,
+        group,
+        humantime::format_rfc3339_seconds(time),
+        attachment,
+    );
+    println!("{} {}", header.cyan(), message.text.yellow());
+}
+
+/// Upload a file in chunks, ready to be sent with [`Message::SendFile`].
+fn upload_file(client: &Client, data: &[u8]) -> io::Result<Result<(), String>> {
+    // An empty file is still uploaded as one empty chunk.
+    for start in (0..data.len().max(1)).step_by(MAX_CHUNK as usize) {
+        let chunk = &data[start..data.len().min(start + MAX_CHUNK as usize)];
+        match client.request(Message::Upload(start as u64, chunk.to_vec()))? {
+            Message::Response(Ok(_)) => {}
+            Message::Response(Err(err)) => return Ok(Err(err)),
+            _ => return Ok(Err("unexpected response".into())),
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Download the attachment of a message in chunks, and save it to a file in
+/// the current directory.
+fn save_attachment(client: &Client, message: &ChatMessage) -> io::Result<()> {
+    let Some(attachment) = &message.attachment else {
+        return Ok(());
+    };
+    // Never write outside the current directory, whatever the sender says.
+    let filename = Path::new(&attachment.filename)
+        .file_name()
+        .map_or("attachment".into(), |name| name.to_string_lossy());
+    let path = PathBuf::from(format!("{}-{filename}", message.id));
+    let mut file = match File::create(&path) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("{} {}", "error:".red(), err);
+            return Ok(());
+        }
+    };
+    let mut offset = 0;
+    while offset < attachment.size {
+        let error = match client.request(Message::Download(message.id, offset, MAX_CHUNK))? {
+            Message::Response(Ok(Reply::Data(chunk))) if !chunk.is_empty() => {
+                offset += chunk.len() as u64;
+                match file.write_all(&chunk) {
+                    Ok(()) => continue,
+                    Err(err) => err.to_string(),
+                }
+            }
+            Message::Response(Err(err)) => err,
+            _ => "unexpected response".into(),
+        };
+        eprintln!("{} {}", "error:".red(), error);
+        _ = fs::remove_file(&path);
+        return Ok(());
+    }
+    eprintln!("{}", format!("saved to {}", path.display()).magenta());
+    Ok(())
+}
+
+/// Save any attachments and acknowledge messages once they have been shown,
+/// so the server drops them.
+fn ack_messages(client: &Client, messages: &[ChatMessage]) -> io::Result<()> {
+    for message in messages {
+        save_attachment(client, message)?;
+    }
+    if !messages.is_empty() {
+        let ids = messages.iter().map(|message| message.id).collect();
+        client.request(Message::Ack(ids))?;
+    }
+    Ok(())
+}
+
+/// Number of results the client asks for in each page.
+const PAGE_SIZE: u32 = 20;
+
+/// Build the request for the page after `reply`, if it was full.
+fn next_page(request: &Message, reply: &Reply) -> Option<Message> {
+    match (request, reply) {
+        (Message::List(filter, _, limit), Reply::Presence(accounts))
+            if accounts.len() == *limit as usize =>
+        {
+            let after = accounts.last()?.name.clone();
+            Some(Message::List(filter.clone(), after, *limit))
+        }
+        (Message::Deliver(name, _, limit), Reply::Messages(messages))
+            if messages.len() == *limit as usize =>
+        {
+            Some(Message::Deliver(name.clone(), messages.last()?.id, *limit))
+        }
+        (Message::Sent(_, limit), Reply::Receipts(receipts))
+            if receipts.len() == *limit as usize =>
+        {
+            Some(Message::Sent(receipts.last()?.id, *limit))
+        }
+        _ => None,
+    }
+}
+
+/// A logged-in session: the account name and its token.
+type Session = (String, String);
+
+fn print_receipt(receipt: &Receipt) {
+    let state = match receipt.state {
+        MessageState::Queued => "queued",
+        MessageState::Delivered => "delivered",
+        MessageState::Read => "read",
+        MessageState::Bounced => "bounced",
+    };
+    let header = format!("#{} to {}", receipt.id, receipt.recipient);
+    println!("{} {}", header.cyan(), state.yellow());
+}
+
+fn print_presence(presence: &Presence) {
+    let status = if presence.online {
+        "online".into()
+    } else if presence.last_seen == 0 {
+        "never seen".into()
+    } else {
+        let time = UNIX_EPOCH + Duration::from_millis(presence.last_seen);
+        format!("last seen {}", humantime::format_rfc3339_seconds(time))
+    };
+    println!("{} {}", presence.name.yellow(), status.cyan());
+}
+
+fn run_client_once(config: &ClientConfig, session: &mut Option<Session>) -> io::Result<()> {
+    let client = Arc::new(Client::connect_with(config)?);
+
+    // Pick up where we left off if the connection was lost, including any
+    // messages that were never acknowledged.
+    if let Some((name, t)) = session.clone() {
+        match client.request(Message::Resume(t))? {
+            Message::Response(Ok(_)) => {
+                eprintln!("{}", "resumed session".magenta());
+                let mut page = Some(Message::Deliver(name, 0, PAGE_SIZE));
+                while let Some(request) = page {
+                    let Message::Response(Ok(reply)) = client.request(request.clone())? else {
+                        break;
+                    };
+                    page = next_page(&request, &reply);
+                    if let Reply::Messages(messages) = reply {
+                        for message in &messages {
+                            print_message(message);
+                        }
+                        ack_messages(&client, &messages)?;
+                    }
+                }
+            }
+            _ => *session = None,
+        }
+    }
+
+    let pushes = client.pushes();
+    let weak = Arc::downgrade(&client);
+    thread::spawn(move || {
+        for message in pushes {
+            match message {
+                Message::Push(message) => {
+                    eprint!("\r");
+                    print_message(&message);
+                    eprint!("{}", "wire> ".green());
+                    if let Some(client) = weak.upgrade() {
+                        _ = ack_messages(&client, &[message]);
+                    }
+                }
+                Message::Receipt(receipt) => {
+                    eprint!("\r");
+                    print_receipt(&receipt);
+                    eprint!("{}", "wire> ".green());
+                }
+                _ => {}
+            }
+        }
+    });
+
+    // The request for the next page of the last list or delivery, if any.
+    let mut more = None;
+
+    // This was also mostly written by Copilot.
+    loop {
+        let mut line = String::new();
+        eprint!("{}", "wire> ".green());
+        if io::stdin().read_line(&mut line)? == 0 {
+            break;
+        }
+        let mut words = line.split_whitespace();
+        let mut login = None;
+        let Some(cmd) = words.next() else { continue };
+        let message = match cmd {
+            "create" => {
+                let (Some(name), Some(password)) = (words.next(), words.next()) else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                Message::Create(name.into(), password.into())
+            }
+            "list" => {
+                let filter = words.next().unwrap_or("");
+                Message::List(filter.into(), String::new(), PAGE_SIZE)
+            }
+            "send" => {
+                let Some(name) = words.next() else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                let text = words.collect::<Vec<_>>().join(" ");
+                Message::Send(name.into(), text)
+            }
+            "attach" => {
+                let (Some(name), Some(path)) = (words.next(), words.next()) else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                let mime = words.next().unwrap_or("application/octet-stream");
+                let data = match fs::read(path) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        eprintln!("{} {}", "error:".red(), err);
+                        continue;
+                    }
+                };
+                if let Err(err) = upload_file(&client, &data)? {
+                    eprintln!("{} {}", "error:".red(), err);
+                    continue;
+                }
+                let filename = Path::new(path).file_name().unwrap_or_default();
+                let filename = filename.to_string_lossy().into_owned();
+                Message::SendFile(name.into(), filename, mime.into())
+            }
+            "deliver" => {
+                let Some(name) = words.next() else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                Message::Deliver(name.into(), 0, PAGE_SIZE)
+            }
+            "next" => match more.take() {
+                Some(request) => request,
+                None => {
+                    eprintln!("no more results");
+                    continue;
+                }
+            },
+            "delete" => {
+                let Some(name) = words.next() else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                let mode = match words.next().map(str::parse).transpose() {
+                    Ok(mode) => mode.unwrap_or_default(),
+                    Err(err) => {
+                        eprintln!("{err}");
+                        continue;
+                    }
+                };
+                Message::Delete(name.into(), mode)
+            }
+            "rename" => {
+                let (Some(name), Some(new_name)) = (words.next(), words.next()) else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                Message::Rename(name.into(), new_name.into())
+            }
+            "login" => {
+                let (Some(name), Some(password)) = (words.next(), words.next()) else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                login = Some(name.to_string());
+                Message::Login(name.into(), password.into())
+            }
+            "logout" => {
+                *session = None;
+                Message::Logout
+            }
+            "group" | "join" | "leave" | "members" => {
+                let Some(group) = words.next() else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                match cmd {
+                    "group" => Message::CreateGroup(group.into()),
+                    "join" => Message::JoinGroup(group.into()),
+                    "leave" => Message::LeaveGroup(group.into()),
+                    _ => Message::Members(group.into()),
+                }
+            }
+            "post" => {
+                let Some(group) = words.next() else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                let text = words.collect::<Vec<_>>().join(" ");
+                Message::SendGroup(group.into(), text)
+            }
+            "sent" => Message::Sent(0, PAGE_SIZE),
+            "block" | "unblock" => {
+                let Some(name) = words.next() else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                if cmd == "block" {
+                    Message::Block(name.into())
+                } else {
+                    Message::Unblock(name.into())
+                }
+            }
+            "unsend" | "edit" => {
+                let Some(Ok(id)) = words.next().map(str::parse) else {
+                    eprintln!("missing or invalid message ID");
+                    continue;
+                };
+                if cmd == "unsend" {
+                    Message::Unsend(id)
+                } else {
+                    Message::Edit(id, words.collect::<Vec<_>>().join(" "))
+                }
+            }
+            "broadcast" => {
+                let Some(pattern) = words.next() else {
+                    eprintln!("missing argument");
+                    continue;
+                };
+                let text = words.collect::<Vec<_>>().join(" ");
+                Message::Broadcast(pattern.into(), text)
+            }
+            _ => {
+                eprintln!("unknown command");
+                continue;
+            }
+        };
+
+        let response = client.request(message.clone())?;
+        more = match &response {
+            Message::Response(Ok(reply)) => next_page(&message, reply),
+            _ => None,
+        };
+        match response {
+            Message::Response(Ok(Reply::Ack)) => {
+                if let (Message::Rename(name, new_name), Some(session)) =
+                    (&message, session.as_mut())
+                {
+                    if session.0 == *name {
+                        session.0 = new_name.clone();
+                    }
+                }
+            }
+            Message::Response(Ok(Reply::Accounts(names))) => {
+                for name in names {
+                    println!("{}", name.yellow());
+                }
+            }
+            Message::Response(Ok(Reply::Messages(messages))) => {
+                for message in &messages {
+                    print_message(message);
+                }
+                ack_messages(&client, &messages)?;
+            }
+            Message::Response(Ok(Reply::Receipts(receipts))) => {
+                for receipt in &receipts {
+                    print_receipt(receipt);
+                }
+            }
+            Message::Response(Ok(Reply::Presence(accounts))) => {
+                for presence in &accounts {
+                    print_presence(presence);
+                }
+            }
+            Message::Response(Ok(Reply::Token(t))) => {
+                *session = login.map(|name| (name, t));
+            }
+            Message::Response(Err(err)) => eprintln!("{} {}", "error:".red(), err),
+            _ => eprintln!("unexpected response"),
+        }
+        if more.is_some() {
+            eprintln!("{}", "type `next` for more".magenta());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run_client(config: &ClientConfig) {
+    // Reconnect on errors, until the user closes standard input.
+    let mut session = None;
+    while let Err(err) = run_client_once(config, &mut session) {
+        eprintln!("{}", format!("I/O error: {err}").magenta());
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// An account stored in memory by the server.
+struct Account {
+    id: u64,
+    password_hash: String,
+    queue: Vec<ChatMessage>,
+    sent: BTreeMap<u64, Receipt>,
+    blocked: BTreeSet<String>,
+    attachments: BTreeMap<u64, Vec<u8>>,
+}
+
+/// In-memory server state, shared by all worker threads.
+#[derive(Clone, Default)]
+struct ServerState {
+    accounts: Arc<Mutex<BTreeMap<String, Account>>>,
+    groups: Arc<Mutex<BTreeMap<String, BTreeSet<String>>>>,
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+    tombstones: Arc<Mutex<BTreeSet<String>>>,
+    sessions: server::Sessions,
+    next_id: Arc<AtomicU64>,
+    next_account_id: Arc<AtomicU64>,
+}
+
+/// Advance the sender's receipt for a message, returning it if it changed.
+fn update_receipt(
+    accounts: &mut BTreeMap<String, Account>,
+    message: &ChatMessage,
+    state: MessageState,
+) -> Option<Receipt> {
+    let receipt = sender_mut(accounts, message)?.sent.get_mut(&message.id)?;
+    if receipt.state >= state {
+        return None;
+    }
+    receipt.state = state;
+    Some(receipt.clone())
+}
+
+/// Find the account that sent a message, by its receipt. Names can be reused,
+/// so this is `None` once the sender's account has been deleted.
+fn sender_mut<'a>(
+    accounts: &'a mut BTreeMap<String, Account>,
+    message: &ChatMessage,
+) -> Option<&'a mut Account> {
+    accounts
+        .get_mut(&message.sender)
+        .filter(|account| account.sent.contains_key(&message.id))
+}
+
+/// Find the recipient of a message from the sender, if it is still queued.
+fn queued_recipient(
+    accounts: &BTreeMap<String, Account>,
+    sender: &str,
+    id: u64,
+) -> Result<String, String> {
+    let receipt = accounts
+        .get(sender)
+        .and_then(|account| account.sent.get(&id))
+        .ok_or("message does not exist")?;
+    if receipt.state != MessageState::Queued {
+        return Err("message already delivered".into());
+    }
+    Ok(receipt.recipient.clone())
+}
+
+impl ServerState {
+    /// Queue a message for a recipient until it is acknowledged, pushing it
+    /// to them if they are online, and record its receipt for the sender.
+    fn enqueue(
+        &self,
+        accounts: &mut BTreeMap<String, Account>,
+        recipient: &str,
+        message: ChatMessage,
+    ) {
+        let pushed = accounts.get(recipient).is_some_and(|account| {
+            self.sessions
+                .push(account.id, &Message::Push(message.clone()))
+        });
+        let state = if pushed {
+            MessageState::Delivered
+        } else {
+            MessageState::Queued
+        };
+        if let Some(sender) = accounts.get_mut(&message.sender) {
+            let receipt = Receipt {
+                id: message.id,
+                recipient: recipient.into(),
+                state,
+            };
+            sender.sent.insert(message.id, receipt);
+        }
+        if let Some(account) = accounts.get_mut(recipient) {
+            account.queue.push(message);
+        }
+    }
+
+    fn handle_message(&self, peer: &Arc<server::Peer>, message: Message) -> Result<Reply, String> {
+        // Most of this part was written by Copilot.
+        match message {
+            Message::Create(name, password) => {
+                eprintln!("create account {name}");
+                let password_hash = auth::hash_password(&password);
+                let mut accounts = self.accounts.lock();
+                if self.tombstones.lock().contains(&name) {
+                    return Err("account already exists".into());
+                }
+                match accounts.entry(name) {
+                    Entry::Occupied(_) => Err("account already exists".into()),
+                    Entry::Vacant(entry) => {
+                        let queue = Vec::new();
+                        entry.insert(Account {
+                            id: self.next_account_id.fetch_add(1, Ordering::Relaxed) + 1,
+                            password_hash,
+                            queue,
+                            sent: BTreeMap::new(),
+                            blocked: BTreeSet::new(),
+                            attachments: BTreeMap::new(),
+                        });
+                        Ok(Reply::Ack)
+                    }
+                }
+            }
+            Message::List(filter, after, limit) => {
+                let matcher = if filter.is_empty() {
+                    WildMatch::new("*")
+                } else {
+                    WildMatch::new(&filter)
+                };
+                let start = if after.is_empty() {
+                    Bound::Unbounded
+                } else {
+                    Bound::Excluded(after.as_str())
+                };
+
+                let accounts = self.accounts.lock();
+                let accounts = accounts
+                    .range::<str, _>((start, Bound::Unbounded))
+                    .filter(|(name, _)| matcher.matches(name))
+                    .take(limit.min(MAX_PAGE) as usize);
+                let presence = accounts.map(|(name, account)| {
+                    let (online, last_seen) = self.sessions.presence(account.id);
+                    Presence {
+                        name: name.clone(),
+                        online,
+                        last_seen,
+                    }
+                });
+                Ok(Reply::Presence(presence.collect()))
+            }
+            Message::Send(name, text) => {
+                eprintln!("send message to {name}");
+                let sender = peer.require_user()?;
+                let mut accounts = self.accounts.lock();
+                if let Some(account) = accounts.get(&name) {
+                    if account.blocked.contains(&sender) {
+                        return Err("blocked by recipient".into());
+                    }
+                    let message = ChatMessage {
+                        id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+                        sender,
+                        group: None,
+                        timestamp: timestamp(),
+                        text,
+                        attachment: None,
+                    };
+                    self.enqueue(&mut accounts, &name, message);
+                    Ok(Reply::Ack)
+                } else {
+                    Err("account does not exist".into())
+                }
+            }
+            Message::Upload(offset, data) => {
+                peer.require_user()?;
+                peer.upload(offset, &data)?;
+                Ok(Reply::Ack)
+            }
+            Message::SendFile(name, filename, mime) => {
+                eprintln!("send file to {name}");
+                let sender = peer.require_user()?;
+                let mut accounts = self.accounts.lock();
+                let Some(account) = accounts.get(&name) else {
+                    return Err("account does not exist".into());
+                };
+                if account.blocked.contains(&sender) {
+                    return Err("blocked by recipient".into());
+                }
+                let data = peer.take_upload()?;
+                let message = ChatMessage {
+                    id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+                    sender,
+                    group: None,
+                    timestamp: timestamp(),
+                    text: String::new(),
+                    attachment: Some(Attachment {
+                        filename,
+                        mime,
+                        size: data.len() as u64,
+                    }),
+                };
+                let id = message.id;
+                // The lock is held, so the contents are stored before the
+                // recipient can ask for them.
+                self.enqueue(&mut accounts, &name, message);
+                if let Some(account) = accounts.get_mut(&name) {
+                    account.attachments.insert(id, data);
+                }
+                Ok(Reply::Ack)
+            }
+            Message::Download(id, offset, limit) => {
+                let name = peer.require_user()?;
+                let accounts = self.accounts.lock();
+                let Some(data) = accounts
+                    .get(&name)
+                    .and_then(|account| account.attachments.get(&id))
+                else {
+                    return Err("attachment does not exist".into());
+                };
+                let start = offset.min(data.len() as u64) as usize;
+                let end = data.len().min(start + limit.min(MAX_CHUNK) as usize);
+                Ok(Reply::Data(data[start..end].to_vec()))
+            }
+            Message::Deliver(name, after, limit) => {
+                eprintln!("deliver messages to {name}");
+                let mut accounts = self.accounts.lock();
+                peer.authorize(accounts.get(&name).map(|account| account.id))?;
+                let Some(account) = accounts.get(&name) else {
+                    return Err("account does not exist".into());
+                };
+                // Messages are queued in order of their IDs.
+                let messages: Vec<_> = account
+                    .queue
+                    .iter()
+                    .filter(|message| message.id > after)
+                    .take(limit.min(MAX_PAGE) as usize)
+                    .cloned()
+                    .collect();
+                for message in &messages {
+                    update_receipt(&mut accounts, message, MessageState::Delivered);
+                }
+                Ok(Reply::Messages(messages))
+            }
+            Message::Ack(ids) => {
+                let name = peer.require_user()?;
+                eprintln!("acknowledge messages for {name}");
+                let mut accounts = self.accounts.lock();
+                let Some(account) = accounts.get_mut(&name) else {
+                    return Err("account does not exist".into());
+                };
+                let (read, queue): (Vec<_>, Vec<_>) = mem::take(&mut account.queue)
+                    .into_iter()
+                    .partition(|message| ids.contains(&message.id));
+                account.queue = queue;
+                for message in &read {
+                    account.attachments.remove(&message.id);
+                }
+                for message in &read {
+                    if let Some(receipt) =
+                        update_receipt(&mut accounts, message, MessageState::Read)
+                    {
+                        if let Some(sender) = accounts.get(&message.sender) {
+                            self.sessions.push(sender.id, &Message::Receipt(receipt));
+                        }
+                    }
+                }
+                Ok(Reply::Ack)
+            }
+            Message::Delete(name, mode) => {
+                eprintln!("delete account {name} ({mode:?})");
+                let mut accounts = self.accounts.lock();
+                peer.authorize(accounts.get(&name).map(|account| account.id))?;
+                let Entry::Occupied(entry) = accounts.entry(name) else {
+                    return Err("account does not exist".into());
+                };
+                if mode == DeleteMode::Restrict && !entry.get().queue.is_empty() {
+                    return Err("account has messages".into());
+                }
+                let (name, mut account) = entry.remove_entry();
+                for message in account.queue {
+                    let Some(sender) = sender_mut(&mut accounts, &message) else {
+                        continue;
+                    };
+                    if mode != DeleteMode::Bounce {
+                        sender.sent.remove(&message.id);
+                        continue;
+                    }
+                    if let Some(receipt) = sender.sent.get_mut(&message.id) {
+                        receipt.state = MessageState::Bounced;
+                    }
+                    if let Some(data) = account.attachments.remove(&message.id) {
+                        sender.attachments.insert(message.id, data);
+                    }
+                    // Messages are queued in order of their IDs.
+                    let index = sender.queue.partition_point(|m| m.id < message.id);
+                    sender.queue.insert(index, message);
+                }
+                for account in accounts.values_mut() {
+                    account.blocked.remove(&name);
+                }
+                self.groups.lock().retain(|_, members| {
+                    members.remove(&name);
+                    !members.is_empty()
+                });
+                self.tokens.lock().retain(|_, user| *user != name);
+                self.sessions.logout_all(account.id);
+                if mode == DeleteMode::Tombstone {
+                    self.tombstones.lock().insert(name);
+                }
+                Ok(Reply::Ack)
+            }
+            Message::Rename(name, new_name) => {
+                eprintln!("rename account {name} to {new_name}");
+                let mut accounts = self.accounts.lock();
+                peer.authorize(accounts.get(&name).map(|account| account.id))?;
+                if accounts.contains_key(&new_name) || self.tombstones.lock().contains(&new_name) {
+                    return Err("account already exists".into());
+                }
+                let Some(account) = accounts.remove(&name) else {
+                    return Err("account does not exist".into());
+                };
+                let id = account.id;
+                let sent: BTreeSet<u64> = account.sent.keys().copied().collect();
+                accounts.insert(new_name.clone(), account);
+                for account in accounts.values_mut() {
+                    if account.blocked.remove(&name) {
+                        account.blocked.insert(new_name.clone());
+                    }
+                    // Only this account's messages are relabeled, not those
+                    // left by an earlier account with the same name.
+                    for message in &mut account.queue {
+                        if sent.contains(&message.id) {
+                            message.sender = new_name.clone();
+                        }
+                    }
+                    for receipt in account.sent.values_mut() {
+                        if receipt.recipient == name {
+                            receipt.recipient = new_name.clone();
+                        }
+                    }
+                }
+                for members in self.groups.lock().values_mut() {
+                    if members.remove(&name) {
+                        members.insert(new_name.clone());
+                    }
+                }
+                for user in self.tokens.lock().values_mut() {
+                    if *user == name {
+                        *user = new_name.clone();
+                    }
+                }
+                self.sessions.rename(id, &new_name);
+                Ok(Reply::Ack)
+            }
+            Message::Login(name, password) => {
+                eprintln!("login to account {name}");
+                let (id, password_hash) = match self.accounts.lock().get(&name) {
+                    Some(account) => (account.id, account.password_hash.clone()),
+                    None => return Err("account does not exist".into()),
+                };
+                if !auth::verify_password(&password, &password_hash) {
+                    return Err("incorrect password".into());
+                }
+                let token = auth::new_token();
+                self.tokens.lock().insert(token.clone(), name.clone());
+                self.sessions.login(peer, id, &name);
+                Ok(Reply::Token(token))
+            }
+            Message::Logout => match self.sessions.logout(peer) {
+                Some(name) => {
+                    eprintln!("logout from account {name}");
+                    self.tokens.lock().retain(|_, user| *user != name);
+                    Ok(Reply::Ack)
+                }
+                None => Err("not logged in".into()),
+            },
+            Message::Resume(token) => {
+                let Some(name) = self.tokens.lock().get(&token).cloned() else {
+                    return Err("invalid session token".into());
+                };
+                eprintln!("resume session for {name}");
+                let Some(id) = self.accounts.lock().get(&name).map(|account| account.id) else {
+                    return Err("invalid session token".into());
+                };
+                self.sessions.login(peer, id, &name);
+                Ok(Reply::Ack)
+            }
+            Message::CreateGroup(group) => {
+                eprintln!("create group {group}");
+                let name = peer.require_user()?;
+                if group.is_empty() {
+                    return Err("invalid group name".into());
+                }
+                match self.groups.lock().entry(group) {
+                    Entry::Occupied(_) => Err("group already exists".into()),
+                    Entry::Vacant(entry) => {
+                        entry.insert(BTreeSet::from([name]));
+                        Ok(Reply::Ack)
+                    }
+                }
+            }
+            Message::JoinGroup(group) => {
+                eprintln!("join group {group}");
+                let name = peer.require_user()?;
+                match self.groups.lock().get_mut(&group) {
+                    Some(members) => {
+                        if members.insert(name) {
+                            Ok(Reply::Ack)
+                        } else {
+                            Err("already a member of group".into())
+                        }
+                    }
+                    None => Err("group does not exist".into()),
+                }
+            }
+            Message::LeaveGroup(group) => {
+                eprintln!("leave group {group}");
+                let name = peer.require_user()?;
+                let mut groups = self.groups.lock();
+                let Some(members) = groups.get_mut(&group) else {
+                    return Err("group does not exist".into());
+                };
+                if !members.remove(&name) {
+                    return Err("not a member of group".into());
+                }
+                if members.is_empty() {
+                    groups.remove(&group);
+                }
+                Ok(Reply::Ack)
+            }
+            Message::Members(group) => match self.groups.lock().get(&group) {
+                Some(members) => Ok(Reply::Accounts(members.iter().cloned().collect())),
+                None => Err("group does not exist".into()),
+            },
+            Message::SendGroup(group, text) => {
+                eprintln!("send message to group {group}");
+                let sender = peer.require_user()?;
+                let mut accounts = self.accounts.lock();
+                let groups = self.groups.lock();
+                let Some(members) = groups.get(&group) else {
+                    return Err("group does not exist".into());
+                };
+                if !members.contains(&sender) {
+                    return Err("not a member of group".into());
+                }
+                let timestamp = timestamp();
+                for name in members.iter().filter(|name| **name != sender) {
+                    match accounts.get(name) {
+                        Some(account) if !account.blocked.contains(&sender) => {}
+                        _ => continue,
+                    }
+                    let message = ChatMessage {
+                        id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+                        sender: sender.clone(),
+                        group: Some(group.clone()),
+                        timestamp,
+                        text: text.clone(),
+                        attachment: None,
+                    };
+                    self.enqueue(&mut accounts, name, message);
+                }
+                Ok(Reply::Ack)
+            }
+            Message::Broadcast(pattern, text) => {
+                eprintln!("broadcast message to {pattern}");
+                let sender = peer.require_user()?;
+                if pattern.is_empty() {
+                    return Err("invalid pattern".into());
+                }
+                let matcher = WildMatch::new(&pattern);
+                let timestamp = timestamp();
+                let mut accounts = self.accounts.lock();
+                let recipients: Vec<String> = accounts
+                    .iter()
+                    .filter(|(name, account)| {
+                        **name != sender
+                            && matcher.matches(name)
+                            && !account.blocked.contains(&sender)
+                    })
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                for name in &recipients {
+                    let message = ChatMessage {
+                        id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+                        sender: sender.clone(),
+                        group: None,
+                        timestamp,
+                        text: text.clone(),
+                        attachment: None,
+                    };
+                    self.enqueue(&mut accounts, name, message);
+                }
+                Ok(Reply::Accounts(recipients))
+            }
+            Message::Sent(after, limit) => {
+                let name = peer.require_user()?;
+                let accounts = self.accounts.lock();
+                let Some(account) = accounts.get(&name) else {
+                    return Err("account does not exist".into());
+                };
+                let receipts = account
+                    .sent
+                    .range((Bound::Excluded(after), Bound::Unbounded))
+                    .map(|(_, receipt)| receipt.clone())
+                    .take(limit.min(MAX_PAGE) as usize);
+                Ok(Reply::Receipts(receipts.collect()))
+            }
+            Message::Block(blocked) => {
+                let name = peer.require_user()?;
+                eprintln!("block {blocked} for {name}");
+                let mut accounts = self.accounts.lock();
+                if !accounts.contains_key(&blocked) {
+                    return Err("account does not exist".into());
+                }
+                let Some(account) = accounts.get_mut(&name) else {
+                    return Err("account does not exist".into());
+                };
+                if account.blocked.insert(blocked) {
+                    Ok(Reply::Ack)
+                } else {
+                    Err("account already blocked".into())
+                }
+            }
+            Message::Unblock(blocked) => {
+                let name = peer.require_user()?;
+                eprintln!("unblock {blocked} for {name}");
+                let mut accounts = self.accounts.lock();
+                let Some(account) = accounts.get_mut(&name) else {
+                    return Err("account does not exist".into());
+                };
+                if account.blocked.remove(&blocked) {
+                    Ok(Reply::Ack)
+                } else {
+                    Err("account not blocked".into())
+                }
+            }
+            Message::Unsend(id) => {
+                let name = peer.require_user()?;
+                eprintln!("unsend message {id} from {name}");
+                let mut accounts = self.accounts.lock();
+                let recipient = queued_recipient(&accounts, &name, id)?;
+                if let Some(account) = accounts.get_mut(&recipient) {
+                    account.queue.retain(|message| message.id != id);
+                    account.attachments.remove(&id);
+                }
+                if let Some(account) = accounts.get_mut(&name) {
+                    account.sent.remove(&id);
+                }
+                Ok(Reply::Ack)
+            }
+            Message::Edit(id, text) => {
+                let name = peer.require_user()?;
+                eprintln!("edit message {id} from {name}");
+                let mut accounts = self.accounts.lock();
+                let recipient = queued_recipient(&accounts, &name, id)?;
+                let message = accounts
+                    .get_mut(&recipient)
+                    .and_then(|account| account.queue.iter_mut().find(|m| m.id == id));
+                if let Some(message) = message {
+                    message.text = text;
+                }
+                Ok(Reply::Ack)
+            }
+            _ => {
+                eprintln!("unexpected message from client");
+                Err("unexpected message".into())
+            }
+        }
+    }
+}
+
+impl server::Handler for ServerState {
+    fn handle(&mut self, peer: &Arc<server::Peer>, message: Message) -> Message {
+        Message::Response(self.handle_message(peer, message))
+    }
+}
+
+/// Configuration for running a chat server.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Local port to listen on.
+    pub port: u16,
+
+    /// Path of a Unix domain socket to listen on instead of the port.
+    pub unix: Option<PathBuf>,
+
+    /// Local port to accept WebSocket connections on, if any.
+    pub websocket_port: Option<u16>,
+
+    /// Limits on the size of requests.
+    pub limits: Limits,
+
+    /// Space for unfinished uploads across all connections, in bytes.
+    pub max_uploads: u64,
+
+    /// TLS configuration, if connections should be encrypted.
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: WIRE_PORT,
+            unix: None,
+            websocket_port: None,
+            limits: Limits::default(),
+            max_uploads: MAX_UPLOADS,
+            tls: None,
+        }
+    }
+}
+
+/// Configuration for connecting to a chat server.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Local port the server is listening on.
+    pub port: u16,
+
+    /// Path of a Unix domain socket to connect to instead of the port.
+    pub unix: Option<PathBuf>,
+
+    /// TLS configuration, if the connection should be encrypted. This is not
+    /// supported over Unix domain sockets.
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+
+    /// Capability flags to advertise to the server.
+    pub capabilities: u32,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            port: WIRE_PORT,
+            unix: None,
+            tls: None,
+            capabilities: CAPABILITIES,
+        }
+    }
+}
+
+pub fn run_server(config: &ServerConfig) -> io::Result<()> {
+    let listener = match &config.unix {
+        Some(path) => server::Listener::bind_unix(path)?,
+        None => server::Listener::Tcp(TcpListener::bind(("127.0.0.1", config.port))?),
+    };
+    let mut listeners = vec![(listener, server::Protocol::Wire)];
+    if let Some(port) = config.websocket_port {
+        let listener = server::Listener::Tcp(TcpListener::bind(("127.0.0.1", port))?);
+        listeners.push((listener, server::Protocol::WebSocket));
+    }
+
+    // All state for the server is in this threadsafe map.
+    let state = ServerState::default();
+    server::serve(listeners, vec![state; server::WORKERS], config)
 }
@@ -0,0 +1,39 @@
+//! Password hashing and session tokens for chat accounts.
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut buf = [0; N];
+    getrandom::getrandom(&mut buf).expect("failed to read random bytes");
+    buf
+}
+
+/// Hash a password with a random salt, in PHC string format.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::encode_b64(&random_bytes::<16>()).expect("valid salt");
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("failed to hash password")
+        .to_string()
+}
+
+/// Check a password against a hash produced by [`hash_password`].
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Generate a new random session token.
+pub fn new_token() -> String {
+    random_bytes::<16>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
@@ -0,0 +1,185 @@
+//! Client for the chat protocol, with support for pipelined requests.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::Arc,
+    thread,
+};
+
+use parking_lot::Mutex;
+
+use super::{
+    client_handshake_with, tls::TlsStream, ClientConfig, Frame, Limits, Message, CAPABILITIES,
+};
+
+type PendingMap = Arc<Mutex<Option<HashMap<u32, flume::Sender<Message>>>>>;
+
+/// A connection to a chat server.
+///
+/// Requests can be submitted from multiple threads at once, and many requests
+/// may be in flight on the connection. Responses are matched to requests by
+/// their frame identifier.
+pub struct Client {
+    writer: Mutex<(Box<dyn Write + Send>, u32)>,
+    pending: PendingMap,
+    pushes: flume::Receiver<Message>,
+    capabilities: u32,
+}
+
+/// A response that has not yet been received from the server.
+pub struct Pending(flume::Receiver<Message>);
+
+impl Pending {
+    /// Block until the response arrives.
+    pub fn wait(self) -> io::Result<Message> {
+        self.0
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed"))
+    }
+}
+
+impl Client {
+    /// Connect to a server and perform the handshake.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::connect_tcp(addr, None, CAPABILITIES)
+    }
+
+    /// Connect to a server over TLS and perform the handshake.
+    pub fn connect_tls(
+        addr: impl ToSocketAddrs,
+        config: Arc<rustls::ClientConfig>,
+    ) -> io::Result<Self> {
+        Self::connect_tcp(addr, Some(config), CAPABILITIES)
+    }
+
+    /// Connect to a server on a Unix domain socket and perform the handshake.
+    pub fn connect_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::connect_unix_with(path, CAPABILITIES)
+    }
+
+    /// Connect to the server described by a configuration, using whichever
+    /// transport it specifies.
+    pub fn connect_with(config: &ClientConfig) -> io::Result<Self> {
+        match &config.unix {
+            Some(path) => Self::connect_unix_with(path, config.capabilities),
+            None => Self::connect_tcp(
+                ("127.0.0.1", config.port),
+                config.tls.clone(),
+                config.capabilities,
+            ),
+        }
+    }
+
+    fn connect_tcp(
+        addr: impl ToSocketAddrs,
+        tls: Option<Arc<rustls::ClientConfig>>,
+        capabilities: u32,
+    ) -> io::Result<Self> {
+        let socket = TcpStream::connect(addr)?;
+        // Each frame is written in one call, so there's no need to wait for
+        // more data before sending it.
+        socket.set_nodelay(true)?;
+        match tls {
+            Some(config) => {
+                let mut stream = TlsStream::new(socket, config)?;
+                let capabilities = client_handshake_with(&mut stream, capabilities)?;
+                let reader = BufReader::new(stream.try_clone()?);
+                Ok(Self::start(reader, Box::new(stream), capabilities))
+            }
+            None => {
+                let mut stream = socket;
+                let capabilities = client_handshake_with(&mut stream, capabilities)?;
+                let reader = BufReader::new(stream.try_clone()?);
+                Ok(Self::start(reader, Box::new(stream), capabilities))
+            }
+        }
+    }
+
+    fn connect_unix_with(path: impl AsRef<Path>, capabilities: u32) -> io::Result<Self> {
+        let mut stream = UnixStream::connect(path)?;
+        let capabilities = client_handshake_with(&mut stream, capabilities)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self::start(reader, Box::new(stream), capabilities))
+    }
+
+    fn start(
+        mut reader: impl Read + Send + 'static,
+        writer: Box<dyn Write + Send>,
+        capabilities: u32,
+    ) -> Self {
+        let pending: PendingMap = Arc::new(Mutex::new(Some(HashMap::new())));
+        let reader_pending = Arc::clone(&pending);
+        let (push_tx, pushes) = flume::unbounded();
+        thread::spawn(move || {
+            let limits = Limits::default();
+            while let Ok(frame) = Frame::decode(&mut reader, &limits) {
+                // Fail the request if its response could not be decoded.
+                let frame = frame.unwrap_or_else(|rejected| Frame {
+                    id: rejected.id,
+                    message: Message::Response(Err(rejected.reason)),
+                });
+                if frame.id == 0 {
+                    _ = push_tx.send(frame.message);
+                    continue;
+                }
+                let tx = match &mut *reader_pending.lock() {
+                    Some(pending) => pending.remove(&frame.id),
+                    None => break,
+                };
+                match tx {
+                    Some(tx) => _ = tx.send(frame.message),
+                    None => eprintln!("unexpected response id {}", frame.id),
+                }
+            }
+            // Dropping all senders wakes up any remaining waiters.
+            reader_pending.lock().take();
+        });
+
+        Self {
+            writer: Mutex::new((writer, 1)),
+            pending,
+            pushes,
+            capabilities,
+        }
+    }
+
+    /// Capabilities negotiated with the server during the handshake.
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Receiver for messages pushed by the server, which closes when the
+    /// connection does.
+    pub fn pushes(&self) -> flume::Receiver<Message> {
+        self.pushes.clone()
+    }
+
+    /// Send a request without waiting for its response.
+    pub fn submit(&self, message: Message) -> io::Result<Pending> {
+        let (tx, rx) = flume::bounded(1);
+        let mut writer = self.writer.lock();
+        let (stream, next_id) = &mut *writer;
+        let id = *next_id;
+        *next_id = next_id.wrapping_add(1).max(1);
+        match &mut *self.pending.lock() {
+            Some(pending) => pending.insert(id, tx),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection closed",
+                ))
+            }
+        };
+        Frame { id, message }.encode(stream)?;
+        Ok(Pending(rx))
+    }
+
+    /// Send a request and block until its response arrives.
+    pub fn request(&self, message: Message) -> io::Result<Message> {
+        self.submit(message)?.wait()
+    }
+}
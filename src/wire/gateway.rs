@@ -0,0 +1,356 @@
+//! HTTP gateway that translates JSON requests into the chat protocol.
+//!
+//! Each HTTP request is forwarded to the backend server on a new connection,
+//! so the gateway itself is stateless. Clients log in by creating a session,
+//! and then pass its token in an `Authorization: Bearer` header, which the
+//! gateway resumes with [`Message::Resume`] before forwarding the request.
+//!
+//! | Method   | Path                                   | Message                  |
+//! |----------|----------------------------------------|--------------------------|
+//! | `POST`   | `/accounts`                            | [`Message::Create`]      |
+//! | `GET`    | `/accounts?filter=<glob>&after=<name>` | [`Message::List`]        |
+//! | `DELETE` | `/accounts/<name>?mode=<mode>`         | [`Message::Delete`]      |
+//! | `PATCH`  | `/accounts/<name>`                     | [`Message::Rename`]      |
+//! | `POST`   | `/accounts/<name>/messages`            | [`Message::Send`]        |
+//! | `GET`    | `/accounts/<name>/messages?after=<id>` | [`Message::Deliver`]     |
+//! | `POST`   | `/acks`                                | [`Message::Ack`]         |
+//! | `POST`   | `/sessions`                            | [`Message::Login`]       |
+//! | `DELETE` | `/sessions`                            | [`Message::Logout`]      |
+//! | `POST`   | `/groups`                              | [`Message::CreateGroup`] |
+//! | `POST`   | `/groups/<group>/members`              | [`Message::JoinGroup`]   |
+//! | `DELETE` | `/groups/<group>/members`              | [`Message::LeaveGroup`]  |
+//! | `GET`    | `/groups/<group>/members`              | [`Message::Members`]     |
+//! | `POST`   | `/groups/<group>/messages`             | [`Message::SendGroup`]   |
+//! | `POST`   | `/broadcasts`                          | [`Message::Broadcast`]   |
+//! | `GET`    | `/sent?after=<id>`                     | [`Message::Sent`]        |
+//! | `DELETE` | `/sent/<id>`                           | [`Message::Unsend`]      |
+//! | `PUT`    | `/sent/<id>`                           | [`Message::Edit`]        |
+//! | `POST`   | `/blocks`                              | [`Message::Block`]       |
+//! | `DELETE` | `/blocks/<name>`                       | [`Message::Unblock`]     |
+//!
+//! Listing accounts, messages and receipts is paginated by the `after`
+//! parameter, and `limit` sets the page size (at most [`MAX_PAGE`], which is
+//! the default). Accounts are deleted with a `mode` of `restrict` (the
+//! default), `purge`, `bounce` or `tombstone`, as described by [`DeleteMode`].
+//!
+//! Successful responses are JSON objects, and errors are returned as
+//! `{"error": "..."}` with a status code chosen from the error message.
+//! Request bodies larger than a frame under the default [`Limits`] are refused
+//! with status 413.
+
+use std::{
+    io::{self, Read},
+    net::SocketAddr,
+    sync::Arc,
+    thread,
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use super::{Client, ClientConfig, DeleteMode, Limits, Message, Reply, MAX_PAGE};
+
+/// Number of threads handling HTTP requests.
+pub const THREADS: usize = 8;
+
+/// Body of a request to create an account or session.
+#[derive(Deserialize)]
+struct Credentials {
+    name: String,
+    password: String,
+}
+
+/// Body of a request naming a group or account.
+#[derive(Deserialize)]
+struct NameBody {
+    name: String,
+}
+
+/// Body of a request to send a message.
+#[derive(Deserialize)]
+struct SendBody {
+    text: String,
+}
+
+/// Body of a request to acknowledge delivered messages.
+#[derive(Deserialize)]
+struct AckBody {
+    ids: Vec<u64>,
+}
+
+/// Body of a request to broadcast a message.
+#[derive(Deserialize)]
+struct BroadcastBody {
+    pattern: String,
+    text: String,
+}
+
+/// An HTTP error response.
+struct HttpError(u16, String);
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self(status, message.into())
+    }
+
+    /// Map an error returned by the chat server to an HTTP status.
+    fn from_server(message: String) -> Self {
+        let status = match message.as_str() {
+            "not logged in" | "incorrect password" | "invalid session token" => 401,
+            "permission denied" | "not a member of group" | "blocked by recipient" => 403,
+            "account does not exist"
+            | "group does not exist"
+            | "account not blocked"
+            | "message does not exist" => 404,
+            "account already exists"
+            | "account has messages"
+            | "group already exists"
+            | "already a member of group"
+            | "account already blocked"
+            | "message already delivered" => 409,
+            _ => 400,
+        };
+        Self(status, message)
+    }
+}
+
+/// Run the gateway on an address, forwarding requests to a backend server.
+pub fn run(addr: SocketAddr, backend: &ClientConfig) -> anyhow::Result<()> {
+    let server = Arc::new(Server::http(addr).map_err(|err| anyhow::anyhow!(err))?);
+    let backend = ClientConfig {
+        // Messages pushed to a short-lived connection would never be read, so
+        // don't ask for them.
+        capabilities: 0,
+        ..backend.clone()
+    };
+    eprintln!("gateway listening on http://{addr}");
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let backend = backend.clone();
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let (status, body) = match handle(&mut request, &backend) {
+                        Ok(body) => (200, body),
+                        Err(HttpError(status, message)) => (status, json!({ "error": message })),
+                    };
+                    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+                    let response = Response::from_string(body.to_string())
+                        .with_status_code(status)
+                        .with_header(header);
+                    if let Err(err) = request.respond(response) {
+                        eprintln!("error sending response: {err}");
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        _ = thread.join();
+    }
+    Ok(())
+}
+
+fn handle(request: &mut Request, backend: &ClientConfig) -> Result<Value, HttpError> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| percent_decode(s, false))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| HttpError::new(400, "invalid URL encoding"))?;
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    let message = match (request.method(), &segments[..]) {
+        (Method::Post, ["accounts"]) => {
+            let body: Credentials = read_json(request)?;
+            Message::Create(body.name, body.password)
+        }
+        (Method::Get, ["accounts"]) => {
+            let filter = query_param(query, "filter")?.unwrap_or_default();
+            let after = query_param(query, "after")?.unwrap_or_default();
+            Message::List(filter, after, page_limit(query)?)
+        }
+        (Method::Delete, ["accounts", name]) => {
+            let mode = match query_param(query, "mode")? {
+                Some(mode) => mode.parse().map_err(|err| HttpError::new(400, err))?,
+                None => DeleteMode::default(),
+            };
+            Message::Delete(name.to_string(), mode)
+        }
+        (Method::Patch, ["accounts", name]) => {
+            let body: NameBody = read_json(request)?;
+            Message::Rename(name.to_string(), body.name)
+        }
+        (Method::Post, ["accounts", name, "messages"]) => {
+            let body: SendBody = read_json(request)?;
+            Message::Send(name.to_string(), body.text)
+        }
+        (Method::Get, ["accounts", name, "messages"]) => {
+            Message::Deliver(name.to_string(), after_id(query)?, page_limit(query)?)
+        }
+        (Method::Post, ["acks"]) => {
+            let body: AckBody = read_json(request)?;
+            Message::Ack(body.ids)
+        }
+        (Method::Post, ["sessions"]) => {
+            let body: Credentials = read_json(request)?;
+            Message::Login(body.name, body.password)
+        }
+        (Method::Delete, ["sessions"]) => Message::Logout,
+        (Method::Post, ["groups"]) => {
+            let body: NameBody = read_json(request)?;
+            Message::CreateGroup(body.name)
+        }
+        (Method::Post, ["groups", group, "members"]) => Message::JoinGroup(group.to_string()),
+        (Method::Delete, ["groups", group, "members"]) => Message::LeaveGroup(group.to_string()),
+        (Method::Get, ["groups", group, "members"]) => Message::Members(group.to_string()),
+        (Method::Post, ["groups", group, "messages"]) => {
+            let body: SendBody = read_json(request)?;
+            Message::SendGroup(group.to_string(), body.text)
+        }
+        (Method::Post, ["broadcasts"]) => {
+            let body: BroadcastBody = read_json(request)?;
+            Message::Broadcast(body.pattern, body.text)
+        }
+        (Method::Get, ["sent"]) => Message::Sent(after_id(query)?, page_limit(query)?),
+        (Method::Delete, ["sent", id]) => Message::Unsend(message_id(id)?),
+        (Method::Put, ["sent", id]) => {
+            let body: SendBody = read_json(request)?;
+            Message::Edit(message_id(id)?, body.text)
+        }
+        (Method::Post, ["blocks"]) => {
+            let body: NameBody = read_json(request)?;
+            Message::Block(body.name)
+        }
+        (Method::Delete, ["blocks", name]) => Message::Unblock(name.to_string()),
+        (
+            _,
+            ["accounts"]
+            | ["accounts", _]
+            | ["accounts", _, "messages"]
+            | ["acks"]
+            | ["sessions"]
+            | ["groups"]
+            | ["groups", _, "members" | "messages"]
+            | ["broadcasts"]
+            | ["sent"]
+            | ["sent", _]
+            | ["blocks"]
+            | ["blocks", _],
+        ) => {
+            return Err(HttpError::new(405, "method not allowed"));
+        }
+        _ => return Err(HttpError::new(404, "not found")),
+    };
+
+    let client = Client::connect_with(backend).map_err(backend_error)?;
+    if let Some(token) = bearer_token(request) {
+        match client
+            .request(Message::Resume(token))
+            .map_err(backend_error)?
+        {
+            Message::Response(Ok(_)) => {}
+            Message::Response(Err(err)) => return Err(HttpError::from_server(err)),
+            _ => return Err(HttpError::new(502, "unexpected response from server")),
+        }
+    }
+
+    match client.request(message).map_err(backend_error)? {
+        Message::Response(Ok(reply)) => Ok(match reply {
+            Reply::Ack => json!({}),
+            Reply::Accounts(names) => json!({ "accounts": names }),
+            Reply::Messages(messages) => json!({ "messages": messages }),
+            Reply::Token(token) => json!({ "token": token }),
+            Reply::Receipts(receipts) => json!({ "receipts": receipts }),
+            Reply::Presence(accounts) => json!({ "accounts": accounts }),
+            Reply::Data(data) => json!({ "data": data }),
+        }),
+        Message::Response(Err(err)) => Err(HttpError::from_server(err)),
+        _ => Err(HttpError::new(502, "unexpected response from server")),
+    }
+}
+
+fn backend_error(err: io::Error) -> HttpError {
+    HttpError::new(502, format!("error contacting server: {err}"))
+}
+
+/// Parse a JSON request body, which must fit in a frame under the default
+/// limits.
+fn read_json<T: DeserializeOwned>(request: &mut Request) -> Result<T, HttpError> {
+    let max_frame = Limits::default().max_frame;
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max_frame as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| HttpError::new(400, format!("error reading request body: {err}")))?;
+    if body.len() > max_frame {
+        return Err(HttpError::new(413, "request body too large"));
+    }
+    serde_json::from_slice(&body)
+        .map_err(|err| HttpError::new(400, format!("invalid request body: {err}")))
+}
+
+fn bearer_token(request: &Request) -> Option<String> {
+    let header = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))?;
+    let token = header.value.as_str().strip_prefix("Bearer ")?;
+    Some(token.trim().to_string())
+}
+
+fn query_param(query: &str, key: &str) -> Result<Option<String>, HttpError> {
+    for pair in query.split('&') {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        if k == key {
+            return percent_decode(v, true)
+                .map(Some)
+                .ok_or_else(|| HttpError::new(400, "invalid URL encoding"));
+        }
+    }
+    Ok(None)
+}
+
+fn after_id(query: &str) -> Result<u64, HttpError> {
+    match query_param(query, "after")? {
+        Some(after) => message_id(&after),
+        None => Ok(0),
+    }
+}
+
+fn message_id(id: &str) -> Result<u64, HttpError> {
+    id.parse()
+        .map_err(|_| HttpError::new(400, "invalid message ID"))
+}
+
+fn page_limit(query: &str) -> Result<u32, HttpError> {
+    match query_param(query, "limit")? {
+        Some(limit) => limit
+            .parse()
+            .map_err(|_| HttpError::new(400, "invalid limit")),
+        None => Ok(MAX_PAGE),
+    }
+}
+
+/// Decode a percent-encoded URL component, which must be valid UTF-8.
+fn percent_decode(s: &str, plus_as_space: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,853 @@
+//! Connection handling shared by the chat servers.
+//!
+//! All connections are handled by a single event loop thread, which uses
+//! non-blocking sockets to accept clients, decode request frames as bytes
+//! arrive, and write out responses. Requests are handled on a fixed pool of
+//! worker threads, so pipelined requests from one connection may complete and
+//! be answered out of order. Idle connections cost only their buffers.
+//!
+//! Servers can listen on either a TCP port or a Unix domain socket, which carry
+//! exactly the same protocol.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Read, Write},
+    net,
+    os::unix,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    thread,
+};
+
+use mio::{
+    event::Source,
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    Events, Interest, Poll, Registry, Token, Waker,
+};
+use parking_lot::Mutex;
+
+use rustls::ServerConnection;
+
+use super::{
+    accept_hello, timestamp,
+    websocket::{self, WebSocket},
+    Frame, FrameDecoder, Limits, Message, ServerConfig, CAP_PUSH, CAP_RECEIPTS, HELLO_LEN,
+    MAX_ATTACHMENT,
+};
+
+/// Number of worker threads used to handle requests.
+pub const WORKERS: usize = 4;
+
+/// Request handler run by each worker thread.
+pub trait Handler: Send + 'static {
+    /// Handle a request from a client, returning the response.
+    fn handle(&mut self, peer: &Arc<Peer>, message: Message) -> Message;
+}
+
+/// State of a single client connection.
+pub struct Peer {
+    token: Token,
+    capabilities: u32,
+    outbox: flume::Sender<Outgoing>,
+    waker: Arc<Waker>,
+    user: Mutex<Option<(u64, String)>>,
+    sessions: Mutex<Option<Sessions>>,
+    upload: Mutex<Option<Vec<u8>>>,
+    upload_space: Arc<UploadSpace>,
+    closed: AtomicBool,
+}
+
+impl Peer {
+    /// The user logged in on this connection, if any.
+    pub fn user(&self) -> Option<String> {
+        self.user.lock().as_ref().map(|(_, name)| name.clone())
+    }
+
+    /// The ID of the account logged in on this connection, if any. Unlike its
+    /// name, this never changes or refers to a different account.
+    pub fn user_id(&self) -> Option<u64> {
+        self.user.lock().as_ref().map(|(id, _)| *id)
+    }
+
+    /// The logged-in user, or an error if there is none.
+    pub fn require_user(&self) -> Result<String, String> {
+        self.user().ok_or_else(|| "not logged in".into())
+    }
+
+    /// Check that the account with the given ID is the one logged in on this
+    /// connection, where `None` is an account that doesn't exist.
+    pub fn authorize(&self, id: Option<u64>) -> Result<(), String> {
+        let user_id = self.user_id().ok_or("not logged in")?;
+        if id == Some(user_id) {
+            Ok(())
+        } else {
+            Err("permission denied".into())
+        }
+    }
+
+    /// Add a chunk to the attachment being uploaded on this connection. Each
+    /// chunk must start where the last one ended, or at zero to start over.
+    /// The upload is dropped if it grows too large, or if the space shared by
+    /// all unfinished uploads runs out.
+    pub fn upload(&self, offset: u64, data: &[u8]) -> Result<(), String> {
+        let mut upload = self.upload.lock();
+        if offset == 0 {
+            self.discard_upload(&mut upload);
+            *upload = Some(Vec::new());
+        }
+        let Some(buf) = upload.as_mut().filter(|buf| buf.len() as u64 == offset) else {
+            return Err("chunk out of order".into());
+        };
+        if offset + data.len() as u64 > MAX_ATTACHMENT {
+            self.discard_upload(&mut upload);
+            return Err("attachment too large".into());
+        }
+        if !self.upload_space.reserve(data.len() as u64) {
+            self.discard_upload(&mut upload);
+            return Err("server is out of upload space".into());
+        }
+        buf.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Take the attachment uploaded on this connection, to be sent. It is kept
+    /// until then, so a send that fails before taking it can be retried.
+    pub fn take_upload(&self) -> Result<Vec<u8>, String> {
+        let data = self.upload.lock().take().ok_or("no attachment uploaded")?;
+        self.upload_space.release(data.len() as u64);
+        Ok(data)
+    }
+
+    fn discard_upload(&self, upload: &mut Option<Vec<u8>>) {
+        if let Some(buf) = upload.take() {
+            self.upload_space.release(buf.len() as u64);
+        }
+    }
+
+    /// Send a server-initiated message, returning whether it was queued.
+    pub fn push(&self, message: Message) -> bool {
+        self.send(Frame { id: 0, message }, false)
+    }
+
+    /// Queue a frame to be written by the event loop.
+    fn send(&self, frame: Frame, response: bool) -> bool {
+        let outgoing = Outgoing {
+            token: self.token,
+            frame,
+            response,
+        };
+        !self.closed.load(Ordering::SeqCst)
+            && self.outbox.send(outgoing).is_ok()
+            && self.waker.wake().is_ok()
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        let upload = self.upload.get_mut();
+        if let Some(buf) = upload.take() {
+            self.upload_space.release(buf.len() as u64);
+        }
+    }
+}
+
+/// Space for unfinished uploads, shared by all connections so that together
+/// they can't hold more than a fixed number of bytes.
+struct UploadSpace {
+    limit: u64,
+    used: Mutex<u64>,
+}
+
+impl UploadSpace {
+    /// Claim space for some bytes, returning whether there was enough left.
+    fn reserve(&self, len: u64) -> bool {
+        let mut used = self.used.lock();
+        if *used + len > self.limit {
+            return false;
+        }
+        *used += len;
+        true
+    }
+
+    fn release(&self, len: u64) {
+        *self.used.lock() -= len;
+    }
+}
+
+/// A frame queued for a connection by another thread.
+struct Outgoing {
+    token: Token,
+    frame: Frame,
+
+    /// Whether the frame answers a request, rather than being pushed.
+    response: bool,
+}
+
+/// Registry of users who are logged in, and the connections they are on.
+///
+/// Users are identified by account ID, since a name can be renamed away and
+/// then taken by a different account.
+#[derive(Clone, Default)]
+pub struct Sessions {
+    peers: Arc<Mutex<HashMap<u64, Vec<Weak<Peer>>>>>,
+    last_seen: Arc<Mutex<HashMap<u64, u64>>>,
+}
+
+impl Sessions {
+    /// Bind a connection to a user, replacing any previous login on it.
+    pub fn login(&self, peer: &Arc<Peer>, id: u64, name: &str) {
+        self.logout(peer);
+        *peer.user.lock() = Some((id, name.into()));
+        *peer.sessions.lock() = Some(self.clone());
+        let mut sessions = self.peers.lock();
+        sessions.entry(id).or_default().push(Arc::downgrade(peer));
+    }
+
+    /// Unbind the user from a connection, returning their name.
+    pub fn logout(&self, peer: &Arc<Peer>) -> Option<String> {
+        let (id, name) = peer.user.lock().take()?;
+        let mut sessions = self.peers.lock();
+        if let Some(peers) = sessions.get_mut(&id) {
+            let this = Arc::downgrade(peer);
+            peers.retain(|p| p.strong_count() > 0 && !Weak::ptr_eq(p, &this));
+            if peers.is_empty() {
+                sessions.remove(&id);
+            }
+        }
+        self.last_seen.lock().insert(id, timestamp());
+        Some(name)
+    }
+
+    /// Unbind a user from all of their connections, such as when their account
+    /// is deleted.
+    pub fn logout_all(&self, id: u64) {
+        let peers = self.peers.lock().remove(&id).unwrap_or_default();
+        for peer in peers.iter().filter_map(Weak::upgrade) {
+            *peer.user.lock() = None;
+        }
+        self.last_seen.lock().remove(&id);
+    }
+
+    /// Change the name of a user on all of their connections, when their
+    /// account is renamed.
+    pub fn rename(&self, id: u64, new_name: &str) {
+        let peers = self.peers.lock().get(&id).cloned().unwrap_or_default();
+        for peer in peers.iter().filter_map(Weak::upgrade) {
+            *peer.user.lock() = Some((id, new_name.into()));
+        }
+    }
+
+    /// IDs of all users with at least one live connection.
+    pub fn users(&self) -> Vec<u64> {
+        let mut sessions = self.peers.lock();
+        sessions.retain(|_, peers| {
+            peers.retain(|p| {
+                p.upgrade()
+                    .is_some_and(|p| !p.closed.load(Ordering::SeqCst))
+            });
+            !peers.is_empty()
+        });
+        sessions.keys().copied().collect()
+    }
+
+    /// Whether a user has a live connection, and when they were last seen
+    /// (now if they are online, or zero if never).
+    pub fn presence(&self, id: u64) -> (bool, u64) {
+        let online = self.peers.lock().get(&id).is_some_and(|peers| {
+            peers
+                .iter()
+                .filter_map(Weak::upgrade)
+                .any(|p| !p.closed.load(Ordering::SeqCst))
+        });
+        if online {
+            (true, timestamp())
+        } else {
+            (false, self.last_seen.lock().get(&id).copied().unwrap_or(0))
+        }
+    }
+
+    /// Whether a user has a live connection that accepts pushes with the given
+    /// capability.
+    pub fn accepts(&self, id: u64, capability: u32) -> bool {
+        self.peers.lock().get(&id).is_some_and(|peers| {
+            peers
+                .iter()
+                .filter_map(Weak::upgrade)
+                .any(|p| p.capabilities & capability != 0 && !p.closed.load(Ordering::SeqCst))
+        })
+    }
+
+    /// Push a message to every connection of a user that accepts pushes of
+    /// its kind, returning whether it reached at least one of them.
+    pub fn push(&self, id: u64, message: &Message) -> bool {
+        let capability = match message {
+            Message::Receipt(_) => CAP_RECEIPTS,
+            _ => CAP_PUSH,
+        };
+        let peers: Vec<_> = match self.peers.lock().get(&id) {
+            Some(peers) => peers.iter().filter_map(Weak::upgrade).collect(),
+            None => return false,
+        };
+        let mut delivered = false;
+        for peer in peers {
+            if peer.capabilities & capability != 0 {
+                delivered |= peer.push(message.clone());
+            }
+        }
+        delivered
+    }
+}
+
+struct Job {
+    frame: Frame,
+    peer: Arc<Peer>,
+}
+
+const WAKER: Token = Token(0);
+
+/// Size of the buffer used for each read from a socket.
+const READ_CHUNK: usize = 8192;
+
+/// A socket that a server accepts connections on.
+pub enum Listener {
+    Tcp(net::TcpListener),
+    Unix(unix::net::UnixListener),
+}
+
+impl Listener {
+    /// Bind a Unix domain socket, replacing the file at the path if it was
+    /// left behind by a server that is no longer running.
+    pub fn bind_unix(path: &Path) -> io::Result<Self> {
+        match unix::net::UnixListener::bind(path) {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                if unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(err);
+                }
+                fs::remove_file(path)?;
+                Ok(Self::Unix(unix::net::UnixListener::bind(path)?))
+            }
+            result => Ok(Self::Unix(result?)),
+        }
+    }
+}
+
+/// Protocol spoken by clients of a listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// The wire protocol, directly on the socket.
+    Wire,
+
+    /// The wire protocol, inside binary WebSocket messages.
+    WebSocket,
+}
+
+/// Non-blocking version of [`Listener`] for the event loop.
+enum EventListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl EventListener {
+    fn new(listener: Listener) -> io::Result<Self> {
+        Ok(match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                Self::Tcp(TcpListener::from_std(listener))
+            }
+            Listener::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                Self::Unix(UnixListener::from_std(listener))
+            }
+        })
+    }
+
+    fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                // Frames are written in one call, so send them right away.
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+            Self::Unix(listener) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match self {
+            Self::Tcp(listener) => listener,
+            Self::Unix(listener) => listener,
+        }
+    }
+}
+
+/// A connected client socket.
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn source(&mut self) -> &mut dyn Source {
+        match self {
+            Self::Tcp(stream) => stream,
+            Self::Unix(stream) => stream,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Event loop state shared by all connections.
+struct Context {
+    waker: Arc<Waker>,
+    outbox: flume::Sender<Outgoing>,
+    jobs: flume::Sender<Job>,
+    limits: Limits,
+    upload_space: Arc<UploadSpace>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+/// Accept connections from listeners, with one handler per worker thread.
+pub fn serve<H: Handler>(
+    listeners: Vec<(Listener, Protocol)>,
+    handlers: Vec<H>,
+    config: &ServerConfig,
+) -> io::Result<()> {
+    let (job_tx, job_rx) = flume::unbounded::<Job>();
+    for mut handler in handlers {
+        let job_rx = job_rx.clone();
+        thread::spawn(move || {
+            for Job { frame, peer } in job_rx {
+                let message = handler.handle(&peer, frame.message);
+                let response = Frame {
+                    id: frame.id,
+                    message,
+                };
+                peer.send(response, true);
+            }
+        });
+    }
+
+    let mut poll = Poll::new()?;
+    let listeners = listeners
+        .into_iter()
+        .enumerate()
+        .map(|(i, (listener, protocol))| {
+            let mut listener = EventListener::new(listener)?;
+            poll.registry()
+                .register(listener.source(), Token(i + 1), Interest::READABLE)?;
+            Ok((listener, protocol))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let (outbox, outbox_rx) = flume::unbounded();
+    let cx = Context {
+        waker: Arc::new(Waker::new(poll.registry(), WAKER)?),
+        outbox,
+        jobs: job_tx,
+        limits: config.limits,
+        upload_space: Arc::new(UploadSpace {
+            limit: config.max_uploads,
+            used: Mutex::new(0),
+        }),
+        tls: config.tls.clone(),
+    };
+
+    // Tokens are never reused, so frames queued for a closed connection can't
+    // be written to a new one.
+    let mut next_token = listeners.len() + 1;
+    let mut connections = HashMap::new();
+    let mut events = Events::with_capacity(1024);
+    let mut dirty = HashSet::new();
+    loop {
+        if let Err(err) = poll.poll(&mut events, None) {
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        for event in &events {
+            match event.token() {
+                WAKER => {}
+                Token(i) if i <= listeners.len() => loop {
+                    let (listener, protocol) = &listeners[i - 1];
+                    let mut stream = match listener.accept() {
+                        Ok(stream) => stream,
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => {
+                            eprintln!("error accepting connection: {}", err);
+                            break;
+                        }
+                    };
+                    let token = Token(next_token);
+                    next_token += 1;
+                    if let Err(err) =
+                        poll.registry()
+                            .register(stream.source(), token, Interest::READABLE)
+                    {
+                        eprintln!("error registering connection: {}", err);
+                        continue;
+                    }
+                    let conn = match Connection::new(stream, *protocol, &cx) {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            eprintln!("error starting TLS session: {}", err);
+                            continue;
+                        }
+                    };
+                    connections.insert(token, conn);
+                    // The client may have sent its hello before we registered.
+                    dirty.insert(token);
+                },
+                token => {
+                    if let Some(conn) = connections.get_mut(&token) {
+                        if event.is_readable() || event.is_read_closed() || event.is_error() {
+                            conn.read(token, &cx);
+                        }
+                        dirty.insert(token);
+                    }
+                }
+            }
+        }
+
+        for Outgoing {
+            token,
+            frame,
+            response,
+        } in outbox_rx.try_iter()
+        {
+            if let Some(conn) = connections.get_mut(&token) {
+                conn.send_frame(&frame);
+                conn.in_flight -= usize::from(response);
+                dirty.insert(token);
+            }
+        }
+
+        for token in dirty.drain() {
+            let Some(conn) = connections.get_mut(&token) else {
+                continue;
+            };
+            if conn.pending_read {
+                conn.pending_read = false;
+                conn.read(token, &cx);
+            }
+            conn.flush(poll.registry(), token);
+            if conn.is_done() {
+                let mut conn = connections.remove(&token).unwrap();
+                conn.close();
+                _ = poll.registry().deregister(conn.stream.source());
+            }
+        }
+    }
+}
+
+/// State of a connection on the event loop.
+struct Connection {
+    stream: Stream,
+    tls: Option<ServerConnection>,
+    websocket: Option<WebSocket>,
+    hello: Vec<u8>,
+    peer: Option<Arc<Peer>>,
+    decoder: FrameDecoder,
+    write_buf: Vec<u8>,
+    writable: bool,
+    pending_read: bool,
+    in_flight: usize,
+    read_closed: bool,
+    closing: bool,
+    failed: bool,
+}
+
+impl Connection {
+    fn new(stream: Stream, protocol: Protocol, cx: &Context) -> Result<Self, rustls::Error> {
+        let tls = match &cx.tls {
+            Some(config) => {
+                let mut tls = ServerConnection::new(Arc::clone(config))?;
+                // Responses are already buffered without limit in `write_buf`.
+                tls.set_buffer_limit(None);
+                Some(tls)
+            }
+            None => None,
+        };
+        let websocket = match protocol {
+            Protocol::Wire => None,
+            Protocol::WebSocket => Some(WebSocket::new()),
+        };
+        Ok(Self {
+            stream,
+            tls,
+            websocket,
+            hello: Vec::with_capacity(HELLO_LEN),
+            peer: None,
+            decoder: FrameDecoder::new(cx.limits),
+            write_buf: Vec::new(),
+            writable: false,
+            pending_read: true,
+            in_flight: 0,
+            read_closed: false,
+            closing: false,
+            failed: false,
+        })
+    }
+
+    /// Read until the socket would block, handling any complete requests.
+    fn read(&mut self, token: Token, cx: &Context) {
+        let mut chunk = [0; READ_CHUNK];
+        while !self.read_closed && !self.closing {
+            let result = match &mut self.tls {
+                Some(tls) => read_tls(tls, &mut self.stream, &mut chunk),
+                None => self.stream.read(&mut chunk),
+            };
+            match result {
+                Ok(0) => self.read_closed = true,
+                Ok(n) => self.receive_raw(&chunk[..n], token, cx),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    if !matches!(
+                        err.kind(),
+                        io::ErrorKind::ConnectionReset | io::ErrorKind::UnexpectedEof
+                    ) {
+                        eprintln!("connection closed: {err}");
+                    }
+                    self.read_closed = true;
+                    self.failed = true;
+                }
+            }
+        }
+    }
+
+    /// Handle bytes from the socket, unwrapping them if they are carried in
+    /// WebSocket messages.
+    fn receive_raw(&mut self, data: &[u8], token: Token, cx: &Context) {
+        let Some(websocket) = &mut self.websocket else {
+            return self.receive(data, token, cx);
+        };
+        let mut payload = Vec::new();
+        let open = websocket.receive(data, &mut payload, &mut self.write_buf);
+        self.receive(&payload, token, cx);
+        if !open {
+            self.closing = true;
+        } else if self.closing {
+            websocket::encode_normal_close(&mut self.write_buf);
+        }
+    }
+
+    fn receive(&mut self, mut data: &[u8], token: Token, cx: &Context) {
+        if self.peer.is_none() {
+            let n = data.len().min(HELLO_LEN - self.hello.len());
+            self.hello.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            match accept_hello(&self.hello) {
+                None => return,
+                Some(Ok(capabilities)) => {
+                    let welcome = Message::Welcome(super::PROTOCOL_VERSION, capabilities);
+                    self.respond(welcome);
+                    self.peer = Some(Arc::new(Peer {
+                        token,
+                        capabilities,
+                        outbox: cx.outbox.clone(),
+                        waker: Arc::clone(&cx.waker),
+                        user: Mutex::new(None),
+                        sessions: Mutex::new(None),
+                        upload: Mutex::new(None),
+                        upload_space: Arc::clone(&cx.upload_space),
+                        closed: AtomicBool::new(false),
+                    }));
+                }
+                Some(Err(err)) => {
+                    self.respond(Message::Response(Err(err)));
+                    self.closing = true;
+                    return;
+                }
+            }
+        }
+
+        let peer = Arc::clone(self.peer.as_ref().unwrap());
+        self.decoder.extend(data);
+        while let Some(result) = self.decoder.next_frame() {
+            match result {
+                Ok(frame) => {
+                    let peer = Arc::clone(&peer);
+                    self.in_flight += 1;
+                    _ = cx.jobs.send(Job { frame, peer });
+                }
+                Err(rejected) => {
+                    eprintln!("rejected frame: {}", rejected.reason);
+                    self.send_frame(&Frame {
+                        id: rejected.id,
+                        message: Message::Response(Err(rejected.reason)),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Write an unframed handshake message.
+    fn respond(&mut self, message: Message) {
+        let mut buf = Vec::new();
+        message
+            .encode(&mut buf)
+            .expect("writing to a vec should not fail");
+        self.send_bytes(&buf);
+    }
+
+    fn send_frame(&mut self, frame: &Frame) {
+        match self.websocket {
+            Some(_) => {
+                let mut buf = Vec::new();
+                frame.encode_into(&mut buf);
+                self.send_bytes(&buf);
+            }
+            None => frame.encode_into(&mut self.write_buf),
+        }
+    }
+
+    /// Queue bytes to be written, as one message on a WebSocket.
+    fn send_bytes(&mut self, buf: &[u8]) {
+        match self.websocket {
+            Some(_) => websocket::encode_binary(buf, &mut self.write_buf),
+            None => self.write_buf.extend_from_slice(buf),
+        }
+    }
+
+    /// Write buffered output until the socket would block, and update which
+    /// events the connection is interested in.
+    fn flush(&mut self, registry: &Registry, token: Token) {
+        let result = match &mut self.tls {
+            Some(tls) => {
+                tls.writer()
+                    .write_all(&self.write_buf)
+                    .expect("TLS buffer should be unlimited");
+                self.write_buf.clear();
+                write_tls(tls, &mut self.stream)
+            }
+            None => write_some(&mut self.stream, &mut self.write_buf),
+        };
+        if result.is_err() {
+            // The client has gone away, so nothing more can be sent.
+            self.read_closed = true;
+            self.failed = true;
+            self.write_buf.clear();
+            return;
+        }
+
+        let writable = self.has_output();
+        if writable != self.writable {
+            let interest = if writable {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            if registry
+                .reregister(self.stream.source(), token, interest)
+                .is_ok()
+            {
+                self.writable = writable;
+            }
+        }
+    }
+
+    /// Whether there is output that has not been written to the socket yet.
+    fn has_output(&self) -> bool {
+        !self.write_buf.is_empty() || self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+
+    /// Whether the connection should be closed. A client that only shuts down
+    /// its side still gets the responses to the requests it already sent.
+    fn is_done(&self) -> bool {
+        self.failed
+            || ((self.read_closed || self.closing) && self.in_flight == 0 && !self.has_output())
+    }
+
+    fn close(&mut self) {
+        if let Some(peer) = &self.peer {
+            peer.closed.store(true, Ordering::SeqCst);
+            // Log out, so that the user's last-seen time is recorded.
+            let sessions = peer.sessions.lock().take();
+            if let Some(sessions) = sessions {
+                sessions.logout(peer);
+            }
+        }
+    }
+}
+
+/// Read decrypted data from a TLS session, reading more from the socket as
+/// needed. Returns zero at the end of the stream.
+fn read_tls(tls: &mut ServerConnection, stream: &mut Stream, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match tls.reader().read(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+        if tls.read_tls(stream)? == 0 {
+            return Ok(0);
+        }
+        if let Err(err) = tls.process_new_packets() {
+            // Try to tell the client what went wrong before closing.
+            _ = tls.write_tls(stream);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+    }
+}
+
+/// Write encrypted data from a TLS session until the socket would block.
+fn write_tls(tls: &mut ServerConnection, stream: &mut Stream) -> io::Result<()> {
+    while tls.wants_write() {
+        match tls.write_tls(stream) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Write a buffer until the socket would block, removing what was written.
+fn write_some(stream: &mut Stream, buf: &mut Vec<u8>) -> io::Result<()> {
+    let mut written = 0;
+    let result = loop {
+        if written == buf.len() {
+            break Ok(());
+        }
+        match stream.write(&buf[written..]) {
+            Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(err),
+        }
+    };
+    buf.drain(..written);
+    result
+}
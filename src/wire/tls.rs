@@ -0,0 +1,157 @@
+//! Optional TLS transport for the chat protocol, using `rustls`.
+//!
+//! The server loads a certificate chain and private key from PEM files. Clients
+//! pin a single CA certificate, which may be the server's own self-signed
+//! certificate from [`generate_cert`], and trust nothing else.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    ClientConnection, RootCertStore,
+};
+
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let pem = fs::read(path)?;
+    let certs = rustls_pemfile::certs(&mut &pem[..]).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificates found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let pem = fs::read(path)?;
+    rustls_pemfile::private_key(&mut &pem[..])?
+        .ok_or_else(|| invalid_data(format!("no private key found in {}", path.display())))
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Load the TLS configuration for a server from PEM files.
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<rustls::ServerConfig>> {
+    let config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+/// Load the TLS configuration for a client that trusts only the CA
+/// certificates in a PEM file.
+pub fn client_config(ca: &Path) -> io::Result<Arc<rustls::ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca)? {
+        roots.add(cert).map_err(invalid_data)?;
+    }
+    let config = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Generate a self-signed certificate for `localhost` and `127.0.0.1`, writing
+/// it and its private key to PEM files.
+pub fn generate_cert(cert: &Path, key: &Path) -> io::Result<()> {
+    let names = vec!["localhost".into(), "127.0.0.1".into()];
+    let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    fs::write(cert, certified.cert.pem())?;
+    fs::write(key, certified.key_pair.serialize_pem())?;
+    Ok(())
+}
+
+/// A client TLS stream that can be cloned, so that one thread can block on
+/// reads while others write.
+///
+/// The TLS state is only locked while processing data, never while waiting on
+/// the socket.
+pub(crate) struct TlsStream {
+    conn: Arc<Mutex<ClientConnection>>,
+    socket: TcpStream,
+}
+
+impl TlsStream {
+    /// Start a TLS session on a connected socket, verifying the certificate
+    /// against the server's IP address.
+    pub(crate) fn new(socket: TcpStream, config: Arc<rustls::ClientConfig>) -> io::Result<Self> {
+        let name = ServerName::IpAddress(socket.peer_addr()?.ip().into());
+        let conn = ClientConnection::new(config, name).map_err(invalid_data)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            socket,
+        })
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            conn: Arc::clone(&self.conn),
+            socket: self.socket.try_clone()?,
+        })
+    }
+
+    fn write_tls(&mut self, conn: &mut ClientConnection) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0; 4096];
+        loop {
+            match self.conn.lock().reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            let n = self.socket.read(&mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let conn = Arc::clone(&self.conn);
+            let mut conn = conn.lock();
+            let mut data = &raw[..n];
+            while !data.is_empty() {
+                conn.read_tls(&mut data)?;
+                conn.process_new_packets().map_err(invalid_data)?;
+            }
+            // Finish the handshake, and reply to any key updates.
+            self.write_tls(&mut conn)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let conn = Arc::clone(&self.conn);
+        let mut conn = conn.lock();
+        let n = conn.writer().write(buf)?;
+        self.write_tls(&mut conn)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+//! Minimal server side of the WebSocket protocol (RFC 6455), so that browsers
+//! can speak the chat protocol.
+//!
+//! The payloads of binary messages from the client are joined into one byte
+//! stream, which carries the handshake and frames exactly as a raw socket
+//! would. Each handshake message and frame from the server is sent as its own
+//! binary message. Text messages and extensions are not supported.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+/// Largest HTTP upgrade request that will be accepted, in bytes.
+const MAX_REQUEST: usize = 8192;
+
+/// Appended to the client's key to compute the accept header.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+
+/// Header of a frame from the client.
+struct Header {
+    len: usize,
+    fin: bool,
+    opcode: u8,
+    mask: [u8; 4],
+    payload_len: u64,
+}
+
+/// State of a data frame whose payload is still arriving.
+struct DataFrame {
+    mask: [u8; 4],
+    offset: usize,
+    remaining: u64,
+}
+
+/// Decoder for a WebSocket connection, from the upgrade request onwards.
+pub(crate) struct WebSocket {
+    upgraded: bool,
+    buf: Vec<u8>,
+    frame: Option<DataFrame>,
+}
+
+impl WebSocket {
+    pub(crate) fn new() -> Self {
+        Self {
+            upgraded: false,
+            buf: Vec::new(),
+            frame: None,
+        }
+    }
+
+    /// Handle bytes received from the client, appending the payload of any
+    /// binary messages to `payload` and any replies to `output`.
+    ///
+    /// Returns false if the connection should be closed once the output has
+    /// been written.
+    pub(crate) fn receive(
+        &mut self,
+        data: &[u8],
+        payload: &mut Vec<u8>,
+        output: &mut Vec<u8>,
+    ) -> bool {
+        self.buf.extend_from_slice(data);
+        if !self.upgraded {
+            let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                if self.buf.len() > MAX_REQUEST {
+                    output.extend_from_slice(bad_request());
+                    return false;
+                }
+                return true;
+            };
+            match upgrade_response(&self.buf[..end]) {
+                Some(response) => output.extend_from_slice(response.as_bytes()),
+                None => {
+                    output.extend_from_slice(bad_request());
+                    return false;
+                }
+            }
+            self.upgraded = true;
+            self.buf.drain(..end + 4);
+        }
+
+        match self.decode_frames(payload, output) {
+            Ok(open) => open,
+            Err(code) => {
+                encode_close(code, output);
+                false
+            }
+        }
+    }
+
+    fn decode_frames(&mut self, payload: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<bool, u16> {
+        let mut pos = 0;
+        let result = loop {
+            if let Some(frame) = &mut self.frame {
+                let n = ((self.buf.len() - pos) as u64).min(frame.remaining) as usize;
+                for &b in &self.buf[pos..pos + n] {
+                    payload.push(b ^ frame.mask[frame.offset % 4]);
+                    frame.offset += 1;
+                }
+                pos += n;
+                frame.remaining -= n as u64;
+                if frame.remaining > 0 {
+                    break Ok(true);
+                }
+                self.frame = None;
+            }
+
+            let Some(header) = parse_header(&self.buf[pos..])? else {
+                break Ok(true);
+            };
+            let Header {
+                len: header_len,
+                fin,
+                opcode,
+                mask,
+                payload_len: len,
+            } = header;
+            match opcode {
+                OP_CONTINUATION | OP_BINARY => {
+                    pos += header_len;
+                    self.frame = Some(DataFrame {
+                        mask,
+                        offset: 0,
+                        remaining: len,
+                    });
+                }
+                OP_TEXT => break Err(CLOSE_UNSUPPORTED_DATA),
+                OP_CLOSE | OP_PING | OP_PONG => {
+                    if !fin || len > 125 {
+                        break Err(CLOSE_PROTOCOL_ERROR);
+                    }
+                    let end = pos + header_len + len as usize;
+                    if self.buf.len() < end {
+                        break Ok(true);
+                    }
+                    let body: Vec<u8> = self.buf[pos + header_len..end]
+                        .iter()
+                        .enumerate()
+                        .map(|(i, b)| b ^ mask[i % 4])
+                        .collect();
+                    pos = end;
+                    match opcode {
+                        OP_CLOSE => {
+                            // Echo the status code back, if there was one.
+                            encode(OP_CLOSE, &body[..body.len().min(2)], output);
+                            break Ok(false);
+                        }
+                        OP_PING => encode(OP_PONG, &body, output),
+                        _ => {}
+                    }
+                }
+                _ => break Err(CLOSE_PROTOCOL_ERROR),
+            }
+        };
+        self.buf.drain(..pos);
+        result
+    }
+}
+
+/// Parse a frame header, or return `None` if more bytes are needed.
+fn parse_header(buf: &[u8]) -> Result<Option<Header>, u16> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    // Reserved bits are only used by extensions, and clients must mask.
+    if buf[0] & 0x70 != 0 || buf[1] & 0x80 == 0 {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let (len, ext) = match buf[1] & 0x7f {
+        126 => match buf.get(2..4) {
+            Some(b) => (u16::from_be_bytes(b.try_into().unwrap()) as u64, 2),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(b) => (u64::from_be_bytes(b.try_into().unwrap()), 8),
+            None => return Ok(None),
+        },
+        len => (len as u64, 0),
+    };
+    let Some(mask) = buf.get(2 + ext..6 + ext) else {
+        return Ok(None);
+    };
+    Ok(Some(Header {
+        len: 6 + ext,
+        fin,
+        opcode,
+        mask: mask.try_into().unwrap(),
+        payload_len: len,
+    }))
+}
+
+/// Check an upgrade request, returning the response that accepts it.
+fn upgrade_response(request: &[u8]) -> Option<String> {
+    let request = std::str::from_utf8(request).ok()?;
+    let mut lines = request.split("\r\n");
+    if !lines.next()?.starts_with("GET ") {
+        return None;
+    }
+    let (mut upgrade, mut version, mut key) = (false, false, None);
+    for line in lines {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value),
+            _ => {}
+        }
+    }
+    if !upgrade || !version {
+        return None;
+    }
+    let digest = Sha1::new()
+        .chain_update(key?.as_bytes())
+        .chain_update(GUID.as_bytes())
+        .finalize();
+    Some(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        STANDARD.encode(digest)
+    ))
+}
+
+fn bad_request() -> &'static [u8] {
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+}
+
+/// Encode an unmasked frame from the server.
+fn encode(opcode: u8, payload: &[u8], output: &mut Vec<u8>) {
+    output.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => output.push(len as u8),
+        len @ 126..=0xffff => {
+            output.push(126);
+            output.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            output.push(127);
+            output.extend((len as u64).to_be_bytes());
+        }
+    }
+    output.extend_from_slice(payload);
+}
+
+/// Encode a binary message from the server.
+pub(crate) fn encode_binary(payload: &[u8], output: &mut Vec<u8>) {
+    encode(OP_BINARY, payload, output);
+}
+
+/// Encode a close frame with a status code.
+fn encode_close(code: u16, output: &mut Vec<u8>) {
+    encode(OP_CLOSE, &code.to_be_bytes(), output);
+}
+
+/// Encode a close frame for a normal closure by the server.
+pub(crate) fn encode_normal_close(output: &mut Vec<u8>) {
+    encode_close(1000, output);
+}
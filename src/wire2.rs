@@ -3,42 +3,213 @@
 //! The server here differs in using a persistent SQLite database to store the
 //! messages, rather than an in-memory data structure. It also can bind to the
 //! same address multiple times, for fault-tolerance.
+//!
+//! Messages sent to a user logged in on the same server process are pushed
+//! directly. Since a user might be logged in on a different process, each
+//! server also polls the database for new messages and pushes those addressed
+//! to its own logged-in users. The same sweeper records which users are
+//! logged in on its process with a heartbeat, so that every process can tell
+//! who is online.
 
 use std::{
+    io,
     net::{Ipv4Addr, SocketAddrV4, TcpListener},
+    sync::Arc,
     thread,
+    time::{Duration, Instant},
 };
 
-use rusqlite::{Connection, OptionalExtension};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
 use socket2::{Domain, Socket, Type};
-use wildmatch::WildMatch;
 
-use crate::wire::{self, Message, WIRE_PORT};
+use crate::wire::{
+    self, auth,
+    server::{self, Peer, Sessions},
+    Attachment, ChatMessage, ClientConfig, DeleteMode, Message, MessageState, Presence, Receipt,
+    Reply, ServerConfig, CAP_PUSH,
+};
 
 pub const DATABASE_FILE: &str = "chat.sqlite";
 
+/// How often to poll the database for messages pushed by other processes.
+const PUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long reads are kept for the sweepers of all processes to see them.
+const READ_EVENT_TTL: Duration = Duration::from_secs(60);
+
+/// How often each process records the users logged in on it.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a user stays online after the last heartbeat that saw them, in
+/// case their server process has exited.
+const PRESENCE_TTL: Duration = Duration::from_secs(15);
+
+/// Longest time the sweeper waits before retrying after a database error.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
 fn db_connect() -> rusqlite::Result<Connection> {
     let conn = Connection::open(DATABASE_FILE)?;
+    conn.busy_timeout(Duration::from_secs(5))?;
     conn.execute("PRAGMA foreign_keys = ON;", [])?;
     Ok(conn)
 }
 
+/// Schema migrations, applied in order and tracked by `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    // 1: accounts and their queued messages
+    "CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE RESTRICT,
+        message TEXT NOT NULL
+    );",
+    // 2: never reuse message IDs, since they are used to poll for new messages
+    "CREATE TABLE messages_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE RESTRICT,
+        message TEXT NOT NULL
+    );
+    INSERT INTO messages_new SELECT id, user_id, message FROM messages;
+    DROP TABLE messages;
+    ALTER TABLE messages_new RENAME TO messages;",
+    // 3: sender and server timestamp of each message
+    "ALTER TABLE messages ADD COLUMN sender TEXT NOT NULL DEFAULT '';
+    ALTER TABLE messages ADD COLUMN timestamp INTEGER NOT NULL DEFAULT 0;",
+    // 4: passwords and session tokens (older accounts cannot log in)
+    "ALTER TABLE users ADD COLUMN password_hash TEXT;
+    CREATE TABLE sessions (
+        token TEXT PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE
+    );",
+    // 5: groups and their members, and the group each message was sent to
+    "CREATE TABLE chat_groups (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE group_members (
+        group_id INTEGER NOT NULL REFERENCES chat_groups(id) ON DELETE CASCADE,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        PRIMARY KEY (group_id, user_id)
+    );
+    CREATE TRIGGER delete_empty_groups AFTER DELETE ON group_members
+    WHEN NOT EXISTS (SELECT 1 FROM group_members WHERE group_id = OLD.group_id)
+    BEGIN
+        DELETE FROM chat_groups WHERE id = OLD.group_id;
+    END;
+    ALTER TABLE messages ADD COLUMN group_name TEXT;",
+    // 6: delivery status of each message for its sender, and recent reads so
+    // that senders on any process can be notified
+    "CREATE TABLE receipts (
+        message_id INTEGER PRIMARY KEY,
+        sender_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        recipient TEXT NOT NULL,
+        state INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX receipts_by_sender ON receipts (sender_id, message_id);
+    CREATE TRIGGER record_receipt AFTER INSERT ON messages
+    BEGIN
+        INSERT INTO receipts (message_id, sender_id, recipient)
+        SELECT NEW.id, sender.id, recipient.name FROM users AS sender, users AS recipient
+        WHERE sender.name = NEW.sender AND recipient.id = NEW.user_id;
+    END;
+    CREATE TABLE read_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        message_id INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL
+    );",
+    // 7: accounts that each user refuses messages from
+    "CREATE TABLE blocks (
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        blocked_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        PRIMARY KEY (user_id, blocked_id)
+    );",
+    // 8: names of deleted accounts that can never be used again
+    "CREATE TABLE tombstones (
+        name TEXT PRIMARY KEY
+    );
+    CREATE TRIGGER reserve_tombstones BEFORE INSERT ON users
+    WHEN EXISTS (SELECT 1 FROM tombstones WHERE name = NEW.name)
+    BEGIN
+        SELECT RAISE(ABORT, 'account already exists');
+    END;",
+    // 9: tombstoned names also cannot be taken by renaming an account
+    "CREATE TRIGGER reserve_tombstones_on_rename BEFORE UPDATE OF name ON users
+    WHEN EXISTS (SELECT 1 FROM tombstones WHERE name = NEW.name)
+    BEGIN
+        SELECT RAISE(ABORT, 'account already exists');
+    END;",
+    // 10: when each user was last connected, and the processes they are
+    // connected to now
+    "ALTER TABLE users ADD COLUMN last_seen INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE presence (
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        server_id TEXT NOT NULL,
+        heartbeat INTEGER NOT NULL,
+        PRIMARY KEY (user_id, server_id)
+    );",
+    // 11: files attached to messages
+    "ALTER TABLE messages ADD COLUMN attachment_name TEXT;
+    ALTER TABLE messages ADD COLUMN attachment_type TEXT;
+    ALTER TABLE messages ADD COLUMN attachment BLOB;",
+    // 12: never reuse user IDs, since connections are bound to them
+    "CREATE TABLE users_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE,
+        password_hash TEXT,
+        last_seen INTEGER NOT NULL DEFAULT 0
+    );
+    INSERT INTO users_new (id, name, password_hash, last_seen)
+    SELECT id, name, password_hash, last_seen FROM users;
+    DROP TABLE users;
+    ALTER TABLE users_new RENAME TO users;
+    CREATE TRIGGER reserve_tombstones BEFORE INSERT ON users
+    WHEN EXISTS (SELECT 1 FROM tombstones WHERE name = NEW.name)
+    BEGIN
+        SELECT RAISE(ABORT, 'account already exists');
+    END;
+    CREATE TRIGGER reserve_tombstones_on_rename BEFORE UPDATE OF name ON users
+    WHEN EXISTS (SELECT 1 FROM tombstones WHERE name = NEW.name)
+    BEGIN
+        SELECT RAISE(ABORT, 'account already exists');
+    END;",
+    // 13: attachments split into chunks of `MAX_CHUNK` bytes, so that each
+    // download reads only the chunk it needs
+    "ALTER TABLE messages ADD COLUMN attachment_size INTEGER;
+    CREATE TABLE attachment_chunks (
+        message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+        offset INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (message_id, offset)
+    );
+    INSERT INTO attachment_chunks (message_id, offset, data)
+    WITH RECURSIVE chunks (message_id, offset, size) AS (
+        SELECT id, 0, length(attachment) FROM messages WHERE length(attachment) > 0
+        UNION ALL
+        SELECT message_id, offset + 32768, size FROM chunks WHERE offset + 32768 < size
+    )
+    SELECT message_id, offset, substr(attachment, offset + 1, 32768)
+    FROM chunks JOIN messages ON messages.id = chunks.message_id;
+    UPDATE messages SET attachment_size = length(attachment), attachment = NULL
+    WHERE attachment IS NOT NULL;",
+];
+
 fn db_initialize() -> rusqlite::Result<()> {
-    let conn = db_connect()?;
-    conn.execute_batch(
-        "BEGIN;
-        CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY,
-            name TEXT NOT NULL UNIQUE
-        );
-        CREATE TABLE IF NOT EXISTS messages (
-            id INTEGER PRIMARY KEY,
-            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE RESTRICT,
-            message TEXT NOT NULL
-        );
-        COMMIT;",
-    )?;
-    Ok(())
+    let mut conn = db_connect()?;
+    // Tables that others refer to are rebuilt by some migrations, which would
+    // otherwise cascade to the rows that refer to them, or fail to parse the
+    // triggers that refer to them while they are being replaced.
+    conn.pragma_update(None, "foreign_keys", false)?;
+    conn.pragma_update(None, "legacy_alter_table", true)?;
+    let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: usize = txn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        txn.execute_batch(migration)?;
+        txn.pragma_update(None, "user_version", i + 1)?;
+    }
+    txn.commit()
 }
 
 struct HandleError(String);
@@ -49,87 +220,418 @@ impl<T: ToString> From<T> for HandleError {
     }
 }
 
-fn handle_message(conn: &mut Connection, message: Message) -> Result<String, HandleError> {
+fn find_user(conn: &Connection, name: &str) -> Result<u64, HandleError> {
+    let mut stmt = conn.prepare_cached("SELECT id FROM users WHERE name = ?")?;
+    match stmt.query_row([name], |row| row.get(0)).optional()? {
+        Some(user_id) => Ok(user_id),
+        None => Err("account does not exist".into()),
+    }
+}
+
+/// Translate a wildcard filter into an SQLite `GLOB` pattern, escaping the
+/// brackets that `GLOB` would treat as character classes.
+fn glob_pattern(filter: &str) -> String {
+    if filter.is_empty() {
+        "*".into()
+    } else {
+        filter.replace('[', "[[]")
+    }
+}
+
+/// Columns of the messages table read by [`chat_message`].
+const MESSAGE_COLUMNS: &str = "messages.id, sender, group_name, timestamp, message, \
+    attachment_name, attachment_type, attachment_size";
+
+/// Read a [`ChatMessage`] from the [`MESSAGE_COLUMNS`].
+fn chat_message(row: &rusqlite::Row) -> rusqlite::Result<ChatMessage> {
+    let attachment = match row.get::<_, Option<String>>(5)? {
+        Some(filename) => Some(Attachment {
+            filename,
+            mime: row.get(6)?,
+            size: row.get(7)?,
+        }),
+        None => None,
+    };
+    Ok(ChatMessage {
+        id: row.get(0)?,
+        sender: row.get(1)?,
+        group: row.get(2)?,
+        timestamp: row.get(3)?,
+        text: row.get(4)?,
+        attachment,
+    })
+}
+
+/// The account logged in on this connection, looked up by its ID in case
+/// another process has renamed or deleted it since.
+fn current_user(
+    conn: &Connection,
+    sessions: &Sessions,
+    peer: &Arc<Peer>,
+) -> Result<(u64, String), HandleError> {
+    let user_id = peer.user_id().ok_or("not logged in")?;
+    let mut stmt = conn.prepare_cached("SELECT name FROM users WHERE id = ?")?;
+    match stmt.query_row([user_id], |row| row.get(0)).optional()? {
+        Some(name) => {
+            if peer.user().as_ref() != Some(&name) {
+                sessions.rename(user_id, &name);
+            }
+            Ok((user_id, name))
+        }
+        None => {
+            sessions.logout_all(user_id);
+            Err("not logged in".into())
+        }
+    }
+}
+
+/// Check that the named account is the one logged in on this connection,
+/// returning its ID.
+fn authorize(
+    conn: &Connection,
+    sessions: &Sessions,
+    peer: &Arc<Peer>,
+    name: &str,
+) -> Result<u64, HandleError> {
+    let (user_id, _) = current_user(conn, sessions, peer)?;
+    let mut stmt = conn.prepare_cached("SELECT id FROM users WHERE name = ?")?;
+    peer.authorize(stmt.query_row([name], |row| row.get(0)).optional()?)?;
+    Ok(user_id)
+}
+
+/// Check that a message from the sender exists and has not been delivered.
+fn check_queued(conn: &Connection, sender_id: u64, id: u64) -> Result<(), HandleError> {
+    let mut stmt =
+        conn.prepare_cached("SELECT state FROM receipts WHERE message_id = ? AND sender_id = ?")?;
+    match stmt
+        .query_row((id, sender_id), |row| row.get::<_, u8>(0))
+        .optional()?
+    {
+        None => Err("message does not exist".into()),
+        Some(0) => Ok(()),
+        Some(_) => Err("message already delivered".into()),
+    }
+}
+
+/// Read a [`Receipt`] from the columns `message_id, recipient, state`.
+fn receipt(row: &rusqlite::Row) -> rusqlite::Result<Receipt> {
+    let state = match row.get::<_, u8>(2)? {
+        0 => MessageState::Queued,
+        1 => MessageState::Delivered,
+        2 => MessageState::Read,
+        3 => MessageState::Bounced,
+        state => {
+            let err = format!("invalid message state {state}").into();
+            return Err(rusqlite::Error::FromSqlConversionFailure(
+                2,
+                rusqlite::types::Type::Integer,
+                err,
+            ));
+        }
+    };
+    Ok(Receipt {
+        id: row.get(0)?,
+        recipient: row.get(1)?,
+        state,
+    })
+}
+
+/// Advance the receipt of a message that has reached its recipient.
+fn mark_delivered(conn: &Connection, id: u64) -> rusqlite::Result<()> {
+    conn.prepare_cached("UPDATE receipts SET state = 1 WHERE message_id = ? AND state < 1")?
+        .execute([id])?;
+    Ok(())
+}
+
+/// Push a message to its recipient as it is now, unless it has been unsent or
+/// read, and mark it delivered if any connection took it. The write lock is
+/// held throughout, so the sender can't change it in between.
+fn push_message(
+    conn: &mut Connection,
+    sessions: &Sessions,
+    user_id: u64,
+    id: u64,
+) -> rusqlite::Result<()> {
+    let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let message = txn
+        .prepare_cached(&format!(
+            "SELECT {MESSAGE_COLUMNS} FROM messages WHERE id = ?"
+        ))?
+        .query_row([id], chat_message)
+        .optional()?;
+    if let Some(message) = message {
+        if sessions.push(user_id, &Message::Push(message)) {
+            mark_delivered(&txn, id)?;
+        }
+    }
+    txn.commit()
+}
+
+/// Map a failure to insert or rename a user to an error for the client.
+fn name_error(err: rusqlite::Error) -> HandleError {
+    let str = err.to_string();
+    if str.contains("UNIQUE constraint failed: users.name")
+        || str.contains("account already exists")
+    {
+        "account already exists".into()
+    } else {
+        str.into()
+    }
+}
+
+fn check_blocked(conn: &Connection, user_id: u64, sender: &str) -> Result<(), HandleError> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT 1 FROM blocks JOIN users ON users.id = blocks.blocked_id
+        WHERE blocks.user_id = ? AND users.name = ?",
+    )?;
+    if stmt.exists((user_id, sender))? {
+        return Err("blocked by recipient".into());
+    }
+    Ok(())
+}
+
+fn find_group(conn: &Connection, group: &str) -> Result<u64, HandleError> {
+    let mut stmt = conn.prepare_cached("SELECT id FROM chat_groups WHERE name = ?")?;
+    match stmt.query_row([group], |row| row.get(0)).optional()? {
+        Some(group_id) => Ok(group_id),
+        None => Err("group does not exist".into()),
+    }
+}
+
+fn is_member(conn: &Connection, group_id: u64, user_id: u64) -> rusqlite::Result<bool> {
+    let mut stmt =
+        conn.prepare_cached("SELECT 1 FROM group_members WHERE group_id = ? AND user_id = ?")?;
+    stmt.exists((group_id, user_id))
+}
+
+fn handle_message(
+    conn: &mut Connection,
+    sessions: &Sessions,
+    wake: &flume::Sender<()>,
+    peer: &Arc<Peer>,
+    message: Message,
+) -> Result<Reply, HandleError> {
     match message {
-        Message::Create(name) => {
+        Message::Create(name, password) => {
             eprintln!("create account {name}");
-            let mut stmt = conn.prepare_cached("INSERT INTO users (name) VALUES (?)")?;
-            stmt.execute([&name])?;
-            Ok("".into())
-        }
-        Message::List(filter) => {
-            let matcher = if filter.is_empty() {
-                WildMatch::new("*")
-            } else {
-                WildMatch::new(&filter)
-            };
-
-            let mut stmt = conn.prepare_cached("SELECT name FROM users")?;
-            let names = match stmt.query_map([], |row| row.get(0)) {
-                Ok(rows) => rows.collect::<Result<Vec<String>, _>>()?,
-                Err(err) => {
-                    let str = err.to_string();
-                    if str.contains("UNIQUE constraint failed: users.name") {
-                        return Err("account already exists".into());
-                    } else {
-                        return Err(str.into());
-                    }
-                }
-            };
-            let mut results = String::new();
-            for name in names {
-                if matcher.matches(&name) {
-                    results += &name;
-                    results += "\n";
-                }
+            let password_hash = auth::hash_password(&password);
+            let mut stmt =
+                conn.prepare_cached("INSERT INTO users (name, password_hash) VALUES (?, ?)")?;
+            match stmt.execute([&name, &password_hash]) {
+                Ok(_) => Ok(Reply::Ack),
+                Err(err) => Err(name_error(err)),
             }
-            Ok(results)
         }
-        Message::Send(name, text) => {
-            eprintln!("send message to {name}");
+        Message::List(filter, after, limit) => {
+            // Filter in the query, so that each page is filled from the index.
             let mut stmt = conn.prepare_cached(
-                "INSERT INTO messages (user_id, message)
-                VALUES ((SELECT id FROM users WHERE name = ?), ?)",
+                "SELECT name, EXISTS (
+                    SELECT 1 FROM presence WHERE user_id = users.id AND heartbeat >= ?4
+                ), last_seen
+                FROM users WHERE (?1 = '' OR name > ?1) AND name GLOB ?2
+                ORDER BY name LIMIT ?3",
             )?;
-            match stmt.execute([&name, &text]) {
-                Ok(_) => Ok("".into()),
-                Err(err) => {
-                    let str = err.to_string();
-                    if str.contains("NOT NULL constraint failed: messages.user_id") {
-                        Err("account does not exist".into())
-                    } else {
-                        Err(str.into())
-                    }
-                }
+            let now = wire::timestamp();
+            let cutoff = now.saturating_sub(PRESENCE_TTL.as_millis() as u64);
+            let accounts = stmt
+                .query_map(
+                    (
+                        &after,
+                        glob_pattern(&filter),
+                        limit.min(wire::MAX_PAGE),
+                        cutoff,
+                    ),
+                    |row| {
+                        let online = row.get(1)?;
+                        Ok(Presence {
+                            name: row.get(0)?,
+                            online,
+                            last_seen: if online { now } else { row.get(2)? },
+                        })
+                    },
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Reply::Presence(accounts))
+        }
+        Message::Send(name, text) => {
+            eprintln!("send message to {name}");
+            let (_, sender) = current_user(conn, sessions, peer)?;
+            let user_id = find_user(conn, &name)?;
+            check_blocked(conn, user_id, &sender)?;
+            conn.prepare_cached(
+                "INSERT INTO messages (user_id, sender, timestamp, message)
+                VALUES (?, ?, ?, ?)",
+            )?
+            .execute((user_id, &sender, wire::timestamp(), &text))?;
+
+            // The message stays queued until it is acknowledged, and is pushed
+            // from there by the sweeper, so that it is never pushed twice.
+            _ = wake.try_send(());
+            Ok(Reply::Ack)
+        }
+        Message::Upload(offset, data) => {
+            current_user(conn, sessions, peer)?;
+            peer.upload(offset, &data)?;
+            Ok(Reply::Ack)
+        }
+        Message::SendFile(name, filename, mime) => {
+            eprintln!("send file to {name}");
+            let (_, sender) = current_user(conn, sessions, peer)?;
+            let user_id = find_user(conn, &name)?;
+            check_blocked(conn, user_id, &sender)?;
+            let data = peer.take_upload()?;
+            let txn = conn.transaction()?;
+            let id: u64 = txn
+                .prepare_cached(
+                    "INSERT INTO messages (user_id, sender, timestamp, message,
+                    attachment_name, attachment_type, attachment_size)
+                    VALUES (?, ?, ?, '', ?, ?, ?) RETURNING id",
+                )?
+                .query_row(
+                    (
+                        user_id,
+                        &sender,
+                        wire::timestamp(),
+                        &filename,
+                        &mime,
+                        data.len(),
+                    ),
+                    |row| row.get(0),
+                )?;
+            let chunk = wire::MAX_CHUNK as usize;
+            for (i, data) in data.chunks(chunk).enumerate() {
+                txn.prepare_cached(
+                    "INSERT INTO attachment_chunks (message_id, offset, data) VALUES (?, ?, ?)",
+                )?
+                .execute((id, i * chunk, data))?;
             }
+            txn.commit()?;
+            _ = wake.try_send(());
+            Ok(Reply::Ack)
         }
-        Message::Deliver(name) => {
-            eprintln!("deliver messages to {name}");
+        Message::Download(id, offset, limit) => {
+            let (user_id, _) = current_user(conn, sessions, peer)?;
             let txn = conn.transaction()?;
-            let mut results = String::new();
-            {
-                let mut stmt = txn.prepare_cached("SELECT id FROM users WHERE name = ?")?;
-                let Some(user_id) = stmt.query_row([&name], |row| row.get::<_, u64>(0)).optional()? else {
-                    return Err("account does not exist".into());
-                };
-                let mut stmt = txn
-                    .prepare_cached("DELETE FROM messages WHERE user_id = ? RETURNING message")
-                    .unwrap();
-                for message_result in stmt.query_map([&user_id], |row| row.get(0))? {
-                    let message: String = message_result?;
-                    results += &message;
-                    results += "\n";
+            let exists = txn
+                .prepare_cached(
+                    "SELECT 1 FROM messages
+                    WHERE id = ? AND user_id = ? AND attachment_name IS NOT NULL",
+                )?
+                .exists((id, user_id))?;
+            if !exists {
+                return Err("attachment does not exist".into());
+            }
+            // Only read from the chunk containing the offset, which may return
+            // fewer bytes than asked for.
+            let chunk = wire::MAX_CHUNK as u64;
+            let data: Vec<u8> = txn
+                .prepare_cached(
+                    "SELECT data FROM attachment_chunks WHERE message_id = ? AND offset = ?",
+                )?
+                .query_row((id, offset / chunk * chunk), |row| row.get(0))
+                .optional()?
+                .unwrap_or_default();
+            let start = (offset % chunk).min(data.len() as u64) as usize;
+            let end = data.len().min(start + limit.min(wire::MAX_CHUNK) as usize);
+            Ok(Reply::Data(data[start..end].to_vec()))
+        }
+        Message::Deliver(name, after, limit) => {
+            eprintln!("deliver messages to {name}");
+            let user_id = authorize(conn, sessions, peer, &name)?;
+            // Read and mark the messages together, so none can be edited in
+            // between.
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let messages = txn
+                .prepare_cached(&format!(
+                    "SELECT {MESSAGE_COLUMNS} FROM messages
+                    WHERE user_id = ? AND id > ? ORDER BY id LIMIT ?"
+                ))?
+                .query_map((user_id, after, limit.min(wire::MAX_PAGE)), chat_message)?
+                .collect::<Result<Vec<_>, _>>()?;
+            for message in &messages {
+                mark_delivered(&txn, message.id)?;
+            }
+            txn.commit()?;
+            Ok(Reply::Messages(messages))
+        }
+        Message::Ack(ids) => {
+            let (user_id, name) = current_user(conn, sessions, peer)?;
+            eprintln!("acknowledge messages for {name}");
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let now = wire::timestamp();
+            for id in ids {
+                let deleted = txn
+                    .prepare_cached("DELETE FROM messages WHERE id = ? AND user_id = ?")?
+                    .execute((id, user_id))?;
+                // Bounced messages are read by their own sender, who already
+                // knows where they are.
+                let read = deleted > 0
+                    && txn
+                        .prepare_cached(
+                            "UPDATE receipts SET state = 2 WHERE message_id = ? AND state < 2",
+                        )?
+                        .execute([id])?
+                        > 0;
+                if read {
+                    txn.prepare_cached(
+                        "INSERT INTO read_events (message_id, timestamp) VALUES (?, ?)",
+                    )?
+                    .execute((id, now))?;
                 }
             }
+            let cutoff = now.saturating_sub(READ_EVENT_TTL.as_millis() as u64);
+            txn.prepare_cached("DELETE FROM read_events WHERE timestamp < ?")?
+                .execute([cutoff])?;
             txn.commit()?;
-            Ok(results)
-        }
-        Message::Delete(name) => {
-            eprintln!("delete account {name}");
-            let mut stmt = conn.prepare_cached("DELETE FROM users WHERE name = ?")?;
-            match stmt.execute([&name]) {
-                Ok(0) => Err("account does not exist".into()),
-                Ok(_) => Ok("".into()),
+            // The sweepers notify the senders.
+            _ = wake.try_send(());
+            Ok(Reply::Ack)
+        }
+        Message::Delete(name, mode) => {
+            eprintln!("delete account {name} ({mode:?})");
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let user_id = authorize(&txn, sessions, peer, &name)?;
+            if mode == DeleteMode::Bounce {
+                // Messages go back to the account that sent them, which is
+                // found by its receipt, since a later account may have taken
+                // the sender's name. Those whose sender is gone are dropped.
+                txn.prepare_cached(
+                    "UPDATE receipts SET state = 3 WHERE sender_id != ?1 AND message_id IN (
+                        SELECT id FROM messages WHERE user_id = ?1
+                    )",
+                )?
+                .execute([user_id])?;
+                txn.prepare_cached(
+                    "UPDATE messages SET user_id = (
+                        SELECT sender_id FROM receipts WHERE message_id = messages.id
+                    )
+                    WHERE user_id = ?1 AND id IN (
+                        SELECT message_id FROM receipts WHERE sender_id != ?1
+                    )",
+                )?
+                .execute([user_id])?;
+            }
+            if mode != DeleteMode::Restrict {
+                txn.prepare_cached(
+                    "DELETE FROM receipts WHERE message_id IN
+                    (SELECT id FROM messages WHERE user_id = ?)",
+                )?
+                .execute([user_id])?;
+                txn.prepare_cached("DELETE FROM messages WHERE user_id = ?")?
+                    .execute([user_id])?;
+            }
+            if mode == DeleteMode::Tombstone {
+                txn.prepare_cached("INSERT INTO tombstones (name) VALUES (?)")?
+                    .execute([&name])?;
+            }
+            let mut stmt = txn.prepare_cached("DELETE FROM users WHERE id = ?")?;
+            match stmt.execute([user_id]) {
+                Ok(_) => {
+                    drop(stmt);
+                    txn.commit()?;
+                    sessions.logout_all(user_id);
+                    Ok(Reply::Ack)
+                }
                 Err(err) => {
                     let str = err.to_string();
                     if str.contains("FOREIGN KEY constraint failed") {
@@ -140,47 +642,464 @@ fn handle_message(conn: &mut Connection, message: Message) -> Result<String, Han
                 }
             }
         }
+        Message::Rename(name, new_name) => {
+            eprintln!("rename account {name} to {new_name}");
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let user_id = authorize(&txn, sessions, peer, &name)?;
+            txn.prepare_cached("UPDATE users SET name = ? WHERE id = ?")?
+                .execute((&new_name, user_id))
+                .map_err(name_error)?;
+            // Groups, blocks and sessions refer to the user by ID, but messages
+            // and receipts store the name of the other party. Messages are
+            // matched to this account by their receipts, so that those left by
+            // an earlier account with the same name keep it.
+            txn.prepare_cached(
+                "UPDATE messages SET sender = ?
+                WHERE id IN (SELECT message_id FROM receipts WHERE sender_id = ?)",
+            )?
+            .execute((&new_name, user_id))?;
+            txn.prepare_cached("UPDATE receipts SET recipient = ? WHERE recipient = ?")?
+                .execute([&new_name, &name])?;
+            txn.commit()?;
+            // Connections to other processes pick up the new name on their
+            // next request.
+            sessions.rename(user_id, &new_name);
+            Ok(Reply::Ack)
+        }
+        Message::Login(name, password) => {
+            eprintln!("login to account {name}");
+            let mut stmt =
+                conn.prepare_cached("SELECT id, password_hash FROM users WHERE name = ?")?;
+            let Some((user_id, password_hash)) = stmt
+                .query_row([&name], |row| {
+                    Ok((row.get::<_, u64>(0)?, row.get::<_, Option<String>>(1)?))
+                })
+                .optional()?
+            else {
+                return Err("account does not exist".into());
+            };
+            if !auth::verify_password(&password, password_hash.as_deref().unwrap_or_default()) {
+                return Err("incorrect password".into());
+            }
+            let token = auth::new_token();
+            let mut stmt =
+                conn.prepare_cached("INSERT INTO sessions (token, user_id) VALUES (?, ?)")?;
+            stmt.execute((&token, user_id))?;
+            sessions.login(peer, user_id, &name);
+            // The sweeper records that the user is online.
+            _ = wake.try_send(());
+            Ok(Reply::Token(token))
+        }
+        Message::Logout => match (peer.user_id(), sessions.logout(peer)) {
+            (Some(user_id), Some(name)) => {
+                eprintln!("logout from account {name}");
+                conn.execute("DELETE FROM sessions WHERE user_id = ?", [user_id])?;
+                _ = wake.try_send(());
+                Ok(Reply::Ack)
+            }
+            _ => Err("not logged in".into()),
+        },
+        Message::Resume(token) => {
+            let mut stmt = conn.prepare_cached(
+                "SELECT users.id, users.name FROM sessions
+                JOIN users ON users.id = sessions.user_id WHERE token = ?",
+            )?;
+            let Some((user_id, name)) = stmt
+                .query_row([&token], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))
+                .optional()?
+            else {
+                return Err("invalid session token".into());
+            };
+            eprintln!("resume session for {name}");
+            sessions.login(peer, user_id, &name);
+            _ = wake.try_send(());
+            Ok(Reply::Ack)
+        }
+        Message::CreateGroup(group) => {
+            eprintln!("create group {group}");
+            let (user_id, _) = current_user(conn, sessions, peer)?;
+            if group.is_empty() {
+                return Err("invalid group name".into());
+            }
+            let txn = conn.transaction()?;
+            let group_id: u64 = match txn
+                .prepare_cached("INSERT INTO chat_groups (name) VALUES (?) RETURNING id")?
+                .query_row([&group], |row| row.get(0))
+            {
+                Ok(group_id) => group_id,
+                Err(err) if err.to_string().contains("UNIQUE constraint failed") => {
+                    return Err("group already exists".into());
+                }
+                Err(err) => return Err(err.into()),
+            };
+            txn.prepare_cached("INSERT INTO group_members (group_id, user_id) VALUES (?, ?)")?
+                .execute((group_id, user_id))?;
+            txn.commit()?;
+            Ok(Reply::Ack)
+        }
+        Message::JoinGroup(group) => {
+            eprintln!("join group {group}");
+            let (user_id, _) = current_user(conn, sessions, peer)?;
+            let group_id = find_group(conn, &group)?;
+            let mut stmt = conn.prepare_cached(
+                "INSERT OR IGNORE INTO group_members (group_id, user_id) VALUES (?, ?)",
+            )?;
+            match stmt.execute((group_id, user_id))? {
+                0 => Err("already a member of group".into()),
+                _ => Ok(Reply::Ack),
+            }
+        }
+        Message::LeaveGroup(group) => {
+            eprintln!("leave group {group}");
+            let (user_id, _) = current_user(conn, sessions, peer)?;
+            let group_id = find_group(conn, &group)?;
+            // Empty groups are deleted by a trigger.
+            let mut stmt = conn
+                .prepare_cached("DELETE FROM group_members WHERE group_id = ? AND user_id = ?")?;
+            match stmt.execute((group_id, user_id))? {
+                0 => Err("not a member of group".into()),
+                _ => Ok(Reply::Ack),
+            }
+        }
+        Message::Members(group) => {
+            let group_id = find_group(conn, &group)?;
+            let mut stmt = conn.prepare_cached(
+                "SELECT users.name FROM group_members
+                JOIN users ON users.id = group_members.user_id
+                WHERE group_id = ? ORDER BY users.name",
+            )?;
+            let names = stmt
+                .query_map([group_id], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            Ok(Reply::Accounts(names))
+        }
+        Message::SendGroup(group, text) => {
+            eprintln!("send message to group {group}");
+            let (sender_id, sender) = current_user(conn, sessions, peer)?;
+            let txn = conn.transaction()?;
+            let group_id = find_group(&txn, &group)?;
+            if !is_member(&txn, group_id, sender_id)? {
+                return Err("not a member of group".into());
+            }
+            // Fan out into the queue of every other member who hasn't blocked
+            // the sender.
+            txn.prepare_cached(
+                "INSERT INTO messages (user_id, sender, group_name, timestamp, message)
+                SELECT user_id, ?1, ?2, ?3, ?4 FROM group_members
+                WHERE group_id = ?5 AND user_id != ?6
+                AND user_id NOT IN (SELECT user_id FROM blocks WHERE blocked_id = ?6)",
+            )?
+            .execute((
+                &sender,
+                &group,
+                wire::timestamp(),
+                &text,
+                group_id,
+                sender_id,
+            ))?;
+            txn.commit()?;
+            _ = wake.try_send(());
+            Ok(Reply::Ack)
+        }
+        Message::Broadcast(pattern, text) => {
+            eprintln!("broadcast message to {pattern}");
+            let (_, sender) = current_user(conn, sessions, peer)?;
+            if pattern.is_empty() {
+                return Err("invalid pattern".into());
+            }
+            // Either every recipient gets the message, or none of them do.
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let recipients = {
+                let mut stmt = txn.prepare_cached(
+                    "INSERT INTO messages (user_id, sender, timestamp, message)
+                    SELECT id, ?1, ?2, ?3 FROM users WHERE name GLOB ?4 AND name != ?1
+                    AND id NOT IN (
+                        SELECT user_id FROM blocks JOIN users AS blocked
+                        ON blocked.id = blocks.blocked_id WHERE blocked.name = ?1
+                    )
+                    RETURNING (SELECT name FROM users WHERE id = user_id)",
+                )?;
+                let rows = stmt.query_map(
+                    (&sender, wire::timestamp(), &text, glob_pattern(&pattern)),
+                    |row| row.get(0),
+                )?;
+                let mut names = rows.collect::<Result<Vec<String>, _>>()?;
+                names.sort();
+                names
+            };
+            txn.commit()?;
+            _ = wake.try_send(());
+            Ok(Reply::Accounts(recipients))
+        }
+        Message::Sent(after, limit) => {
+            let (sender_id, _) = current_user(conn, sessions, peer)?;
+            let mut stmt = conn.prepare_cached(
+                "SELECT message_id, recipient, state FROM receipts
+                WHERE sender_id = ? AND message_id > ? ORDER BY message_id LIMIT ?",
+            )?;
+            let receipts = stmt
+                .query_map((sender_id, after, limit.min(wire::MAX_PAGE)), receipt)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Reply::Receipts(receipts))
+        }
+        Message::Block(blocked) => {
+            let (user_id, name) = current_user(conn, sessions, peer)?;
+            eprintln!("block {blocked} for {name}");
+            let blocked_id = find_user(conn, &blocked)?;
+            let mut stmt = conn.prepare_cached(
+                "INSERT OR IGNORE INTO blocks (user_id, blocked_id) VALUES (?, ?)",
+            )?;
+            match stmt.execute((user_id, blocked_id))? {
+                0 => Err("account already blocked".into()),
+                _ => Ok(Reply::Ack),
+            }
+        }
+        Message::Unblock(blocked) => {
+            let (user_id, name) = current_user(conn, sessions, peer)?;
+            eprintln!("unblock {blocked} for {name}");
+            let mut stmt = conn.prepare_cached(
+                "DELETE FROM blocks WHERE user_id = ?
+                AND blocked_id = (SELECT id FROM users WHERE name = ?)",
+            )?;
+            match stmt.execute((user_id, &blocked))? {
+                0 => Err("account not blocked".into()),
+                _ => Ok(Reply::Ack),
+            }
+        }
+        Message::Unsend(id) => {
+            let (user_id, name) = current_user(conn, sessions, peer)?;
+            eprintln!("unsend message {id} from {name}");
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            check_queued(&txn, user_id, id)?;
+            txn.prepare_cached("DELETE FROM messages WHERE id = ?")?
+                .execute([id])?;
+            txn.prepare_cached("DELETE FROM receipts WHERE message_id = ?")?
+                .execute([id])?;
+            txn.commit()?;
+            Ok(Reply::Ack)
+        }
+        Message::Edit(id, text) => {
+            let (user_id, name) = current_user(conn, sessions, peer)?;
+            eprintln!("edit message {id} from {name}");
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            check_queued(&txn, user_id, id)?;
+            txn.prepare_cached("UPDATE messages SET message = ? WHERE id = ?")?
+                .execute((&text, id))?;
+            txn.commit()?;
+            Ok(Reply::Ack)
+        }
         _ => {
             eprintln!("unexpected message from client");
-            Ok("".into())
+            Err("unexpected message".into())
         }
     }
 }
 
-pub fn run_client() {
-    // The application client remains the same as before.
-    wire::run_client()
+struct DbHandler {
+    conn: Connection,
+    sessions: Sessions,
+    wake: flume::Sender<()>,
 }
 
-pub fn run_server() -> anyhow::Result<()> {
-    // Connect to the database and initialize tables.
-    db_initialize()?;
+impl server::Handler for DbHandler {
+    fn handle(&mut self, peer: &Arc<Peer>, message: Message) -> Message {
+        let resp = handle_message(&mut self.conn, &self.sessions, &self.wake, peer, message);
+        Message::Response(resp.map_err(|err| err.0))
+    }
+}
+
+/// Push newly queued messages to users logged in on this process, whether they
+/// were sent here or by other processes.
+///
+/// Pushed messages stay queued until they are acknowledged. The sweeper polls
+/// the database, and is woken up early whenever a message is sent or read
+/// here. It also notifies senders when their messages are read, and keeps the
+/// presence of this process's users up to date.
+///
+/// Database errors, such as a busy timeout while other processes hold locks,
+/// are logged and retried with backoff, so the sweeper never stops.
+fn push_messages(sessions: Sessions, wake: flume::Receiver<()>) {
+    let mut delay = PUSH_INTERVAL;
+    let retry = |err: rusqlite::Error, delay: &mut Duration| {
+        eprintln!("error pushing messages: {err}");
+        thread::sleep(*delay);
+        *delay = (*delay * 2).min(MAX_RETRY_DELAY);
+    };
+    let mut sweeper = loop {
+        match Sweeper::new(sessions.clone()) {
+            Ok(sweeper) => break sweeper,
+            Err(err) => retry(err, &mut delay),
+        }
+    };
+    loop {
+        _ = wake.recv_timeout(PUSH_INTERVAL);
+        wake.drain();
+        match sweeper.sweep() {
+            Ok(()) => delay = PUSH_INTERVAL,
+            Err(err) => retry(err, &mut delay),
+        }
+    }
+}
+
+/// State of the sweeper, kept across polls of the database.
+struct Sweeper {
+    conn: Connection,
+    sessions: Sessions,
+    server_id: String,
+    present: Vec<u64>,
+    last_heartbeat: Instant,
+    last_id: u64,
+    last_read: u64,
+}
+
+impl Sweeper {
+    fn new(sessions: Sessions) -> rusqlite::Result<Self> {
+        let conn = db_connect()?;
+        // Only messages sent after startup are pushed; older messages stay
+        // queued until they are delivered on demand.
+        let last_id = conn.query_row("SELECT coalesce(max(id), 0) FROM messages", [], |row| {
+            row.get(0)
+        })?;
+        let last_read =
+            conn.query_row("SELECT coalesce(max(id), 0) FROM read_events", [], |row| {
+                row.get(0)
+            })?;
+        Ok(Self {
+            conn,
+            sessions,
+            server_id: auth::new_token(),
+            present: Vec::new(),
+            last_heartbeat: Instant::now(),
+            last_id,
+            last_read,
+        })
+    }
+
+    /// Poll the database once, pushing anything new since the last poll.
+    fn sweep(&mut self) -> rusqlite::Result<()> {
+        let conn = &mut self.conn;
+        let sessions = &self.sessions;
+        let mut users = sessions.users();
+        users.sort();
+        if users != self.present || self.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            heartbeat(conn, &self.server_id, &users, &self.present)?;
+            self.present.clone_from(&users);
+            self.last_heartbeat = Instant::now();
+        }
+        // Finish reading before pushing, so acknowledgements aren't blocked.
+        let new_messages = {
+            let mut stmt =
+                conn.prepare_cached("SELECT id, user_id FROM messages WHERE id > ? ORDER BY id")?;
+            let rows = stmt.query_map([self.last_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<Vec<(u64, u64)>, _>>()?
+        };
+        for (id, user_id) in new_messages {
+            if sessions.accepts(user_id, CAP_PUSH) {
+                push_message(conn, sessions, user_id, id)?;
+            }
+            self.last_id = id;
+        }
 
+        // Notify senders that their messages have been read.
+        let reads = {
+            let mut stmt = conn.prepare_cached(
+                "SELECT message_id, recipient, state, read_events.id, sender_id
+                FROM read_events JOIN receipts USING (message_id)
+                WHERE read_events.id > ? ORDER BY read_events.id",
+            )?;
+            let rows = stmt.query_map([self.last_read], |row| {
+                Ok((row.get(3)?, receipt(row)?, row.get(4)?))
+            })?;
+            rows.collect::<Result<Vec<(u64, Receipt, u64)>, _>>()?
+        };
+        for (id, receipt, sender_id) in reads {
+            self.last_read = id;
+            if users.contains(&sender_id) {
+                sessions.push(sender_id, &Message::Receipt(receipt));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replace the users recorded as logged in on this process, updating when
+/// they and any who have left since the last heartbeat were last seen.
+fn heartbeat(
+    conn: &mut Connection,
+    server_id: &str,
+    users: &[u64],
+    previous: &[u64],
+) -> rusqlite::Result<()> {
+    let now = wire::timestamp();
+    let cutoff = now.saturating_sub(PRESENCE_TTL.as_millis() as u64);
+    let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    // Also clear out expired heartbeats from processes that have exited.
+    txn.prepare_cached("DELETE FROM presence WHERE server_id = ? OR heartbeat < ?")?
+        .execute((server_id, cutoff))?;
+    // Accounts deleted by other processes are skipped.
+    for user_id in users {
+        txn.prepare_cached(
+            "INSERT INTO presence (user_id, server_id, heartbeat)
+            SELECT id, ?, ? FROM users WHERE id = ?",
+        )?
+        .execute((server_id, now, user_id))?;
+    }
+    for user_id in users.iter().chain(previous) {
+        txn.prepare_cached("UPDATE users SET last_seen = ? WHERE id = ?")?
+            .execute((now, user_id))?;
+    }
+    txn.commit()
+}
+
+pub fn run_client(config: &ClientConfig) {
+    // The application client remains the same as before.
+    wire::run_client(config)
+}
+
+/// Bind a local port that other server processes can also listen on.
+fn bind_shared(port: u16) -> io::Result<server::Listener> {
     // Set initial socket options to allow reuse of port.
     let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
     socket.set_reuse_address(true)?;
     socket.set_reuse_port(true)?;
-    socket.bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, WIRE_PORT).into())?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into())?;
     socket.listen(128)?;
+    Ok(server::Listener::Tcp(TcpListener::from(socket)))
+}
 
-    let listener = TcpListener::from(socket);
+pub fn run_server(config: &ServerConfig) -> anyhow::Result<()> {
+    // Connect to the database and initialize tables.
+    db_initialize()?;
 
-    for stream in listener.incoming() {
-        let mut stream = match stream {
-            Ok(stream) => stream,
-            Err(err) => {
-                eprintln!("error accepting connection: {}", err);
-                continue;
-            }
-        };
+    let listener = match &config.unix {
+        // Each server process needs its own socket path, since they can't be
+        // shared like ports.
+        Some(path) => server::Listener::bind_unix(path)?,
+        None => bind_shared(config.port)?,
+    };
+    let mut listeners = vec![(listener, server::Protocol::Wire)];
+    if let Some(port) = config.websocket_port {
+        listeners.push((bind_shared(port)?, server::Protocol::WebSocket));
+    }
 
-        let mut conn = db_connect()?;
-        thread::spawn(move || loop {
-            let Ok(message) = Message::decode(&mut stream) else { break };
-            let resp = handle_message(&mut conn, message).map_err(|err| err.0);
-            let Ok(_) = Message::Response(resp).encode(&mut stream) else { break };
-        });
+    let sessions = Sessions::default();
+    let (wake_tx, wake_rx) = flume::bounded(1);
+    {
+        let sessions = sessions.clone();
+        thread::spawn(move || push_messages(sessions, wake_rx));
     }
 
+    // Each worker thread gets its own database connection.
+    let handlers = (0..server::WORKERS)
+        .map(|_| {
+            Ok(DbHandler {
+                conn: db_connect()?,
+                sessions: sessions.clone(),
+                wake: wake_tx.clone(),
+            })
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    server::serve(listeners, handlers, config)?;
+
     Ok(())
 }
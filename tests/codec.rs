@@ -0,0 +1,191 @@
+//! Tests that feed hostile byte streams into the wire decoder.
+
+use std::io::{self, Read, Write};
+
+use cs262::wire::{client_handshake, Frame, FrameDecoder, Limits, Message, PROTOCOL_VERSION};
+
+fn limits() -> Limits {
+    Limits {
+        max_frame: 64,
+        max_field: 16,
+    }
+}
+
+fn frame(id: u32, body: &[u8]) -> Vec<u8> {
+    let mut buf = id.to_be_bytes().to_vec();
+    buf.extend((body.len() as u32).to_be_bytes());
+    buf.extend(body);
+    buf
+}
+
+fn rejected(stream: &mut impl Read) -> (u32, String) {
+    match Frame::decode(stream, &limits()).unwrap() {
+        Ok(frame) => panic!("frame was accepted: {:?}", frame.message),
+        Err(rejected) => (rejected.id, rejected.reason),
+    }
+}
+
+#[test]
+fn roundtrip() {
+    let mut buf = Vec::new();
+    let message = Message::Send("bob".into(), "hello".into());
+    Frame {
+        id: 7,
+        message: message.clone(),
+    }
+    .encode(&mut buf)
+    .unwrap();
+    let frame = Frame::decode(&mut &buf[..], &limits()).unwrap().unwrap();
+    assert_eq!(frame.id, 7);
+    assert_eq!(frame.message, message);
+}
+
+#[test]
+fn oversize_frame_is_skipped() {
+    let mut buf = frame(1, &[2; 1000]);
+    buf.extend(frame(2, &[2, 1, b'*', 0, 0, 0, 0, 100]));
+    let mut stream = &buf[..];
+
+    let (id, reason) = rejected(&mut stream);
+    assert_eq!(id, 1);
+    assert!(reason.contains("exceeds limit"), "{reason}");
+
+    let frame = Frame::decode(&mut stream, &limits()).unwrap().unwrap();
+    assert_eq!(frame.id, 2);
+    assert_eq!(frame.message, Message::List("*".into(), "".into(), 100));
+}
+
+#[test]
+fn huge_frame_length_does_not_allocate() {
+    // Claims to be 4 GiB long, but is backed by an endless stream of zeros.
+    let header = frame(3, &[]);
+    let mut header = header[..4].to_vec();
+    header.extend(u32::MAX.to_be_bytes());
+    let mut stream = (&header[..]).chain(io::repeat(0).take(100));
+    let err = Frame::decode(&mut stream, &limits()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn oversize_field_is_rejected() {
+    // A List request whose filter claims to be 4 GiB long.
+    let body = [2, 255, 255, 255, 255, 255];
+    let (id, reason) = rejected(&mut &frame(4, &body)[..]);
+    assert_eq!(id, 4);
+    assert!(reason.contains("field of 4294967295 bytes"), "{reason}");
+
+    // The same, when decoding a message directly from an endless stream.
+    let mut stream = (&body[..]).chain(io::repeat(b'a'));
+    let err = Message::decode(&mut stream, &limits()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn field_longer_than_frame_is_truncated() {
+    let (_, reason) = rejected(&mut &frame(5, &[2, 10, b'a', b'b'])[..]);
+    assert_eq!(reason, "frame was truncated");
+}
+
+#[test]
+fn huge_list_count_is_truncated() {
+    // An Accounts reply claiming to have 4 billion entries.
+    let (_, reason) = rejected(&mut &frame(6, &[245, 255, 255, 255, 255, 255, 0])[..]);
+    assert_eq!(reason, "frame was truncated");
+}
+
+#[test]
+fn invalid_type_is_rejected() {
+    let (_, reason) = rejected(&mut &frame(7, &[99])[..]);
+    assert_eq!(reason, "wire message had invalid type");
+}
+
+#[test]
+fn invalid_utf8_is_rejected() {
+    let (_, reason) = rejected(&mut &frame(8, &[2, 2, 0xc3, 0x28])[..]);
+    assert_eq!(reason, "wire message had invalid UTF-8");
+}
+
+#[test]
+fn trailing_bytes_are_rejected() {
+    let (_, reason) = rejected(&mut &frame(9, &[7, 0, 0])[..]);
+    assert_eq!(reason, "frame had trailing bytes");
+}
+
+#[test]
+fn stream_ending_mid_frame_is_an_error() {
+    let buf = frame(10, &[2, 5, b'a', b'b', b'c', b'd', b'e']);
+    let err = Frame::decode(&mut &buf[..8], &limits()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    let err = Frame::decode(&mut &buf[..10], &limits()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn incremental_decoding_byte_by_byte() {
+    let mut buf = Vec::new();
+    let first = Frame {
+        id: 11,
+        message: Message::Send("alice".into(), "hi".into()),
+    };
+    let second = Frame {
+        id: 12,
+        message: Message::Logout,
+    };
+    first.encode(&mut buf).unwrap();
+    second.encode(&mut buf).unwrap();
+
+    let mut decoder = FrameDecoder::new(limits());
+    let mut frames = Vec::new();
+    for byte in buf {
+        decoder.extend(&[byte]);
+        while let Some(result) = decoder.next_frame() {
+            frames.push(result.unwrap());
+        }
+    }
+    assert_eq!(frames, [first, second]);
+}
+
+#[test]
+fn incremental_oversize_frame_is_skipped() {
+    let mut decoder = FrameDecoder::new(limits());
+    let buf = frame(13, &[0; 100]);
+    decoder.extend(&buf[..20]);
+    let rejected = decoder.next_frame().unwrap().unwrap_err();
+    assert_eq!(rejected.id, 13);
+    assert!(decoder.next_frame().is_none());
+    decoder.extend(&buf[20..]);
+    decoder.extend(&frame(14, &[7]));
+    let frame = decoder.next_frame().unwrap().unwrap();
+    assert_eq!((frame.id, frame.message), (14, Message::Logout));
+}
+
+/// A stream that reads from a fixed buffer and discards writes.
+struct Canned(io::Cursor<Vec<u8>>);
+
+impl Read for Canned {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Canned {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn handshake_checks_server_version() {
+    let welcome = |version| {
+        let mut buf = Vec::new();
+        Message::Welcome(version, 1).encode(&mut buf).unwrap();
+        Canned(io::Cursor::new(buf))
+    };
+    assert_eq!(client_handshake(&mut welcome(PROTOCOL_VERSION)).unwrap(), 1);
+    let err = client_handshake(&mut welcome(PROTOCOL_VERSION + 1)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+}
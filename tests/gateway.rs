@@ -0,0 +1,149 @@
+//! Tests of the HTTP gateway against an in-process server.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use cs262::wire::{self, gateway, ClientConfig, Limits, ServerConfig};
+use serde_json::{json, Value};
+
+const PORT: u16 = 15725;
+const HTTP_PORT: u16 = 18725;
+
+/// Make an HTTP request, returning the status code and JSON body.
+fn http(method: &str, path: &str, token: Option<&str>, body: Option<Value>) -> (u16, Value) {
+    let mut stream = TcpStream::connect(("127.0.0.1", HTTP_PORT)).unwrap();
+    let body = body.map(|b| b.to_string()).unwrap_or_default();
+    let auth = token
+        .map(|t| format!("Authorization: Bearer {t}\r\n"))
+        .unwrap_or_default();
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{auth}\
+         Content-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn rest_endpoints() {
+    let config = ServerConfig {
+        port: PORT,
+        ..Default::default()
+    };
+    thread::spawn(move || wire::run_server(&config).unwrap());
+    let backend = ClientConfig {
+        port: PORT,
+        ..Default::default()
+    };
+    thread::spawn(move || gateway::run(([127, 0, 0, 1], HTTP_PORT).into(), &backend).unwrap());
+    while TcpStream::connect(("127.0.0.1", HTTP_PORT)).is_err() {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let alice = json!({ "name": "alice", "password": "pw" });
+    let bob = json!({ "name": "bob", "password": "pw" });
+    assert_eq!(http("POST", "/accounts", None, Some(alice.clone())).0, 200);
+    assert_eq!(http("POST", "/accounts", None, Some(bob.clone())).0, 200);
+    assert_eq!(
+        http("POST", "/accounts", None, Some(bob.clone())),
+        (409, json!({ "error": "account already exists" }))
+    );
+    assert_eq!(
+        http("GET", "/accounts?filter=a%2A", None, None),
+        (
+            200,
+            json!({ "accounts": [{ "name": "alice", "online": false, "last_seen": 0 }] })
+        )
+    );
+    assert_eq!(
+        http("GET", "/accounts?after=alice&limit=1", None, None),
+        (
+            200,
+            json!({ "accounts": [{ "name": "bob", "online": false, "last_seen": 0 }] })
+        )
+    );
+
+    let text = json!({ "text": "hi bob" });
+    assert_eq!(
+        http("POST", "/accounts/bob/messages", None, Some(text.clone())),
+        (401, json!({ "error": "not logged in" }))
+    );
+    let (_, session) = http("POST", "/sessions", None, Some(alice));
+    let token = session["token"].as_str().unwrap();
+    assert_eq!(
+        http("POST", "/accounts/bob/messages", Some(token), Some(text)),
+        (200, json!({}))
+    );
+    assert_eq!(
+        http("GET", "/accounts/bob/messages", Some(token), None).0,
+        403
+    );
+
+    // Queued messages can be retracted or edited by their sender.
+    let oops = json!({ "text": "oops" });
+    assert_eq!(
+        http(
+            "POST",
+            "/accounts/bob/messages",
+            Some(token),
+            Some(oops.clone())
+        )
+        .0,
+        200
+    );
+    let (_, sent) = http("GET", "/sent", Some(token), None);
+    let id = &sent["receipts"][1]["id"];
+    assert_eq!(
+        http("DELETE", &format!("/sent/{id}"), Some(token), None).0,
+        200
+    );
+    assert_eq!(
+        http("PUT", &format!("/sent/{id}"), Some(token), Some(oops)),
+        (404, json!({ "error": "message does not exist" }))
+    );
+
+    let (_, session) = http("POST", "/sessions", None, Some(bob));
+    let token = session["token"].as_str().unwrap();
+    let (status, body) = http("GET", "/accounts/bob/messages", Some(token), None);
+    assert_eq!(status, 200);
+    assert_eq!(body["messages"][0]["sender"], "alice");
+    assert_eq!(body["messages"][0]["text"], "hi bob");
+    assert_eq!(
+        http("DELETE", "/accounts/bob", Some(token), None),
+        (409, json!({ "error": "account has messages" }))
+    );
+
+    // Messages stay queued until they are acknowledged.
+    let ids = json!({ "ids": [body["messages"][0]["id"]] });
+    assert_eq!(
+        http("GET", "/accounts/bob/messages", Some(token), None).1,
+        body
+    );
+    assert_eq!(http("POST", "/acks", Some(token), Some(ids)).0, 200);
+    assert_eq!(
+        http("GET", "/accounts/bob/messages", Some(token), None),
+        (200, json!({ "messages": [] }))
+    );
+    assert_eq!(http("DELETE", "/accounts/bob", Some(token), None).0, 200);
+
+    assert_eq!(http("PUT", "/accounts", None, None).0, 405);
+    assert_eq!(http("GET", "/missing", None, None).0, 404);
+
+    // Bodies are refused before they are read past the frame limit.
+    let text = json!({ "text": "x".repeat(Limits::default().max_frame) });
+    assert_eq!(
+        http("POST", "/accounts/alice/messages", None, Some(text)),
+        (413, json!({ "error": "request body too large" }))
+    );
+}
@@ -0,0 +1,245 @@
+//! Golden byte vectors that pin the on-the-wire format of every message.
+//!
+//! If one of these fails, the encoding has changed incompatibly, and the
+//! protocol version needs to be bumped.
+
+use cs262::wire::{
+    Attachment, ChatMessage, DeleteMode, Frame, Limits, Message, MessageState, Presence, Receipt,
+    Reply,
+};
+
+fn check(message: Message, bytes: &[u8]) {
+    let mut buf = Vec::new();
+    message.encode(&mut buf).unwrap();
+    assert_eq!(buf, bytes, "encoding of {message:?}");
+    let decoded = Message::decode(&mut &bytes[..], &Limits::default()).unwrap();
+    assert_eq!(decoded, message);
+}
+
+fn str_of_len(len: usize) -> String {
+    "x".repeat(len)
+}
+
+#[test]
+fn requests() {
+    check(
+        Message::Create("ab".into(), "pw".into()),
+        b"\x01\x02ab\x02pw",
+    );
+    check(
+        Message::List("".into(), "".into(), 10),
+        b"\x02\x00\x00\x00\x00\x00\x0a",
+    );
+    check(
+        Message::List("a*".into(), "ab".into(), 10),
+        b"\x02\x02a*\x02ab\x00\x00\x00\x0a",
+    );
+    check(Message::Send("ab".into(), "hi".into()), b"\x03\x02ab\x02hi");
+    check(
+        Message::Deliver("ab".into(), 1, 10),
+        b"\x04\x02ab\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x0a",
+    );
+    check(
+        Message::Delete("ab".into(), DeleteMode::Restrict),
+        b"\x05\x02ab\x00",
+    );
+    check(
+        Message::Delete("ab".into(), DeleteMode::Tombstone),
+        b"\x05\x02ab\x03",
+    );
+    check(
+        Message::Login("ab".into(), "pw".into()),
+        b"\x06\x02ab\x02pw",
+    );
+    check(Message::Logout, b"\x07");
+    check(Message::Resume("t".into()), b"\x08\x01t");
+    check(
+        Message::Ack(vec![1, 2]),
+        b"\x09\x02\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x02",
+    );
+    check(Message::CreateGroup("g".into()), b"\x0a\x01g");
+    check(Message::JoinGroup("g".into()), b"\x0b\x01g");
+    check(Message::LeaveGroup("g".into()), b"\x0c\x01g");
+    check(Message::Members("g".into()), b"\x0d\x01g");
+    check(
+        Message::SendGroup("g".into(), "hi".into()),
+        b"\x0e\x01g\x02hi",
+    );
+    check(
+        Message::Broadcast("a*".into(), "hi".into()),
+        b"\x0f\x02a*\x02hi",
+    );
+    check(
+        Message::Sent(1, 10),
+        b"\x10\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x0a",
+    );
+    check(Message::Block("ab".into()), b"\x11\x02ab");
+    check(Message::Unblock("ab".into()), b"\x12\x02ab");
+    check(Message::Unsend(7), b"\x13\x00\x00\x00\x00\x00\x00\x00\x07");
+    check(
+        Message::Edit(7, "ab".into()),
+        b"\x14\x00\x00\x00\x00\x00\x00\x00\x07\x02ab",
+    );
+    check(
+        Message::Rename("ab".into(), "cd".into()),
+        b"\x15\x02ab\x02cd",
+    );
+    check(
+        Message::Upload(1, vec![0, 255]),
+        b"\x16\x00\x00\x00\x00\x00\x00\x00\x01\x02\x00\xff",
+    );
+    check(
+        Message::SendFile("ab".into(), "f".into(), "x/y".into()),
+        b"\x17\x02ab\x01f\x03x/y",
+    );
+    check(
+        Message::Download(7, 1, 10),
+        b"\x18\x00\x00\x00\x00\x00\x00\x00\x07\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x0a",
+    );
+}
+
+#[test]
+fn responses() {
+    let message = ChatMessage {
+        id: 1,
+        sender: "ab".into(),
+        group: None,
+        timestamp: 2,
+        text: "hi".into(),
+        attachment: None,
+    };
+    let message_bytes =
+        b"\x00\x00\x00\x00\x00\x00\x00\x01\x02ab\x00\x00\x00\x00\x00\x00\x00\x00\x02\x02hi\x00";
+
+    check(Message::Response(Ok(Reply::Ack)), b"\xf2");
+    check(Message::Response(Err("no".into())), b"\xf3\x02no");
+    check(
+        Message::Push(message.clone()),
+        &[&b"\xf4"[..], message_bytes].concat(),
+    );
+    check(
+        Message::Push(ChatMessage {
+            group: Some("g".into()),
+            ..message.clone()
+        }),
+        b"\xf4\x00\x00\x00\x00\x00\x00\x00\x01\x02ab\x01g\x00\x00\x00\x00\x00\x00\x00\x02\x02hi\x00",
+    );
+    check(
+        Message::Push(ChatMessage {
+            attachment: Some(Attachment {
+                filename: "f".into(),
+                mime: "x/y".into(),
+                size: 3,
+            }),
+            ..message.clone()
+        }),
+        &[
+            &b"\xf4"[..],
+            &message_bytes[..message_bytes.len() - 1],
+            b"\x01\x01f\x03x/y\x00\x00\x00\x00\x00\x00\x00\x03",
+        ]
+        .concat(),
+    );
+    check(
+        Message::Response(Ok(Reply::Accounts(vec!["a".into(), "bc".into()]))),
+        b"\xf5\x02\x01a\x02bc",
+    );
+    check(
+        Message::Response(Ok(Reply::Messages(vec![message]))),
+        &[&b"\xf6\x01"[..], message_bytes].concat(),
+    );
+    check(
+        Message::Response(Ok(Reply::Token("t".into()))),
+        b"\xf7\x01t",
+    );
+
+    let receipt = Receipt {
+        id: 1,
+        recipient: "ab".into(),
+        state: MessageState::Read,
+    };
+    let receipt_bytes = b"\x00\x00\x00\x00\x00\x00\x00\x01\x02ab\x02";
+    check(
+        Message::Response(Ok(Reply::Receipts(vec![receipt.clone()]))),
+        &[&b"\xf8\x01"[..], receipt_bytes].concat(),
+    );
+    check(
+        Message::Receipt(Receipt {
+            state: MessageState::Bounced,
+            ..receipt.clone()
+        }),
+        b"\xf9\x00\x00\x00\x00\x00\x00\x00\x01\x02ab\x03",
+    );
+    check(
+        Message::Receipt(receipt),
+        &[&b"\xf9"[..], receipt_bytes].concat(),
+    );
+    check(
+        Message::Response(Ok(Reply::Presence(vec![Presence {
+            name: "ab".into(),
+            online: true,
+            last_seen: 2,
+        }]))),
+        b"\xfa\x01\x02ab\x01\x00\x00\x00\x00\x00\x00\x00\x02",
+    );
+    check(
+        Message::Response(Ok(Reply::Data(vec![0, 255]))),
+        b"\xfb\x02\x00\xff",
+    );
+}
+
+#[test]
+fn handshake() {
+    check(
+        Message::Hello(6, 1),
+        b"\xf0\x00\x00\x00\x06\x00\x00\x00\x01",
+    );
+    check(
+        Message::Welcome(6, 1),
+        b"\xf1\x00\x00\x00\x06\x00\x00\x00\x01",
+    );
+}
+
+#[test]
+fn length_boundaries() {
+    let cases: [(usize, &[u8]); 5] = [
+        (0, b"\x00"),
+        (254, b"\xfe"),
+        (255, b"\xff\x00\x00\x00\xff"),
+        (256, b"\xff\x00\x00\x01\x00"),
+        (70000, b"\xff\x00\x01\x11\x70"),
+    ];
+    for (len, prefix) in cases {
+        let text = str_of_len(len);
+        let bytes = [
+            &b"\x02"[..],
+            prefix,
+            text.as_bytes(),
+            b"\x00\x00\x00\x00\x00",
+        ]
+        .concat();
+        let mut buf = Vec::new();
+        Message::List(text.clone(), "".into(), 0)
+            .encode(&mut buf)
+            .unwrap();
+        assert_eq!(buf, bytes, "encoding of length {len}");
+        let limits = Limits {
+            max_field: len,
+            ..Limits::default()
+        };
+        let decoded = Message::decode(&mut &bytes[..], &limits).unwrap();
+        assert_eq!(decoded, Message::List(text, "".into(), 0));
+    }
+}
+
+#[test]
+fn frame() {
+    let mut buf = Vec::new();
+    Frame {
+        id: 0x01020304,
+        message: Message::Logout,
+    }
+    .encode(&mut buf)
+    .unwrap();
+    assert_eq!(buf, b"\x01\x02\x03\x04\x00\x00\x00\x01\x07");
+}
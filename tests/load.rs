@@ -0,0 +1,91 @@
+//! Load test for the event-driven server, which is ignored by default since it
+//! needs a large file descriptor limit. Run it in release mode with:
+//!
+//! ```text
+//! cargo test --release --test load -- --ignored
+//! ```
+
+use std::{
+    net::TcpStream,
+    process::{Child, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+use cs262::wire::{client_handshake, Client, Frame, Message, Reply};
+
+const PORT: u16 = 15722;
+const IDLE_CONNECTIONS: usize = 10_000;
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        _ = self.0.kill();
+        _ = self.0.wait();
+    }
+}
+
+fn start_server() -> Server {
+    let server = Server(
+        Command::new(env!("CARGO_BIN_EXE_cs262"))
+            .args(["wire", "server", "--port", &PORT.to_string()])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", PORT)).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("server did not start");
+}
+
+#[test]
+#[ignore]
+fn ten_thousand_idle_connections() {
+    let _server = start_server();
+
+    let start = Instant::now();
+    let mut idle = Vec::with_capacity(IDLE_CONNECTIONS);
+    for _ in 0..IDLE_CONNECTIONS {
+        let mut stream = TcpStream::connect(("127.0.0.1", PORT)).unwrap();
+        client_handshake(&mut stream).unwrap();
+        idle.push(stream);
+    }
+    println!(
+        "opened {IDLE_CONNECTIONS} connections in {:?}",
+        start.elapsed()
+    );
+
+    // New clients are still served promptly while the others sit idle.
+    let start = Instant::now();
+    let client = Client::connect(("127.0.0.1", PORT)).unwrap();
+    let response = client
+        .request(Message::List("*".into(), "".into(), 100))
+        .unwrap();
+    assert!(matches!(
+        response,
+        Message::Response(Ok(Reply::Presence(_)))
+    ));
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // Every idle connection is still alive and answers a request.
+    for (id, stream) in idle.iter_mut().enumerate() {
+        let frame = Frame {
+            id: id as u32 + 1,
+            message: Message::List("*".into(), "".into(), 100),
+        };
+        frame.encode(stream).unwrap();
+    }
+    for (id, stream) in idle.iter_mut().enumerate() {
+        let frame = Frame::decode(stream, &Default::default()).unwrap().unwrap();
+        assert_eq!(frame.id, id as u32 + 1);
+        assert!(matches!(
+            frame.message,
+            Message::Response(Ok(Reply::Presence(_)))
+        ));
+    }
+    println!("all connections answered after {:?}", start.elapsed());
+}
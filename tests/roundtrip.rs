@@ -0,0 +1,173 @@
+//! Property tests that encoding and decoding wire messages are inverses.
+
+use cs262::wire::{
+    Attachment, ChatMessage, DeleteMode, Frame, FrameDecoder, Limits, Message, MessageState,
+    Presence, Receipt, Reply,
+};
+use proptest::prelude::*;
+
+/// Strings with lengths on both sides of the one-byte length boundary.
+fn text() -> impl Strategy<Value = String> {
+    prop_oneof![".{0,20}", "[a-z]{250,260}", ".{0,300}"]
+}
+
+fn chat_message() -> impl Strategy<Value = ChatMessage> {
+    // Group names are never empty, since that encodes a direct message.
+    let group = proptest::option::of(text().prop_filter("empty group", |g| !g.is_empty()));
+    let attachment = proptest::option::of((text(), text(), any::<u64>()).prop_map(
+        |(filename, mime, size)| Attachment {
+            filename,
+            mime,
+            size,
+        },
+    ));
+    (
+        any::<u64>(),
+        text(),
+        group,
+        any::<u64>(),
+        text(),
+        attachment,
+    )
+        .prop_map(
+            |(id, sender, group, timestamp, text, attachment)| ChatMessage {
+                id,
+                sender,
+                group,
+                timestamp,
+                text,
+                attachment,
+            },
+        )
+}
+
+fn receipt() -> impl Strategy<Value = Receipt> {
+    let state = prop_oneof![
+        Just(MessageState::Queued),
+        Just(MessageState::Delivered),
+        Just(MessageState::Read),
+        Just(MessageState::Bounced),
+    ];
+    (any::<u64>(), text(), state).prop_map(|(id, recipient, state)| Receipt {
+        id,
+        recipient,
+        state,
+    })
+}
+
+fn presence() -> impl Strategy<Value = Presence> {
+    (text(), any::<bool>(), any::<u64>()).prop_map(|(name, online, last_seen)| Presence {
+        name,
+        online,
+        last_seen,
+    })
+}
+
+fn reply() -> impl Strategy<Value = Reply> {
+    prop_oneof![
+        Just(Reply::Ack),
+        prop::collection::vec(text(), 0..8).prop_map(Reply::Accounts),
+        prop::collection::vec(chat_message(), 0..8).prop_map(Reply::Messages),
+        text().prop_map(Reply::Token),
+        prop::collection::vec(receipt(), 0..8).prop_map(Reply::Receipts),
+        prop::collection::vec(presence(), 0..8).prop_map(Reply::Presence),
+        prop::collection::vec(any::<u8>(), 0..300).prop_map(Reply::Data),
+    ]
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    prop_oneof![
+        (text(), text()).prop_map(|(a, b)| Message::Create(a, b)),
+        (text(), text(), any::<u32>()).prop_map(|(f, a, n)| Message::List(f, a, n)),
+        (text(), text()).prop_map(|(a, b)| Message::Send(a, b)),
+        (text(), any::<u64>(), any::<u32>()).prop_map(|(s, a, n)| Message::Deliver(s, a, n)),
+        (
+            text(),
+            prop_oneof![
+                Just(DeleteMode::Restrict),
+                Just(DeleteMode::Purge),
+                Just(DeleteMode::Bounce),
+                Just(DeleteMode::Tombstone),
+            ],
+        )
+            .prop_map(|(name, mode)| Message::Delete(name, mode)),
+        (text(), text()).prop_map(|(a, b)| Message::Login(a, b)),
+        Just(Message::Logout),
+        text().prop_map(Message::Resume),
+        prop::collection::vec(any::<u64>(), 0..300).prop_map(Message::Ack),
+        text().prop_map(Message::CreateGroup),
+        text().prop_map(Message::JoinGroup),
+        text().prop_map(Message::LeaveGroup),
+        text().prop_map(Message::Members),
+        (text(), text()).prop_map(|(g, t)| Message::SendGroup(g, t)),
+        (text(), text()).prop_map(|(p, t)| Message::Broadcast(p, t)),
+        (any::<u64>(), any::<u32>()).prop_map(|(a, n)| Message::Sent(a, n)),
+        text().prop_map(Message::Block),
+        text().prop_map(Message::Unblock),
+        any::<u64>().prop_map(Message::Unsend),
+        (any::<u64>(), text()).prop_map(|(id, t)| Message::Edit(id, t)),
+        (text(), text()).prop_map(|(a, b)| Message::Rename(a, b)),
+        (any::<u64>(), prop::collection::vec(any::<u8>(), 0..300))
+            .prop_map(|(o, d)| Message::Upload(o, d)),
+        (text(), text(), text()).prop_map(|(a, f, m)| Message::SendFile(a, f, m)),
+        (any::<u64>(), any::<u64>(), any::<u32>())
+            .prop_map(|(id, o, n)| Message::Download(id, o, n)),
+        reply().prop_map(|r| Message::Response(Ok(r))),
+        text().prop_map(|e| Message::Response(Err(e))),
+        chat_message().prop_map(Message::Push),
+        receipt().prop_map(Message::Receipt),
+        (any::<u32>(), any::<u32>()).prop_map(|(v, c)| Message::Hello(v, c)),
+        (any::<u32>(), any::<u32>()).prop_map(|(v, c)| Message::Welcome(v, c)),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn message_roundtrip(message in message()) {
+        let mut buf = Vec::new();
+        message.encode(&mut buf).unwrap();
+        let mut rest = &buf[..];
+        let decoded = Message::decode(&mut rest, &Limits::default()).unwrap();
+        prop_assert_eq!(decoded, message);
+        prop_assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn frame_roundtrip(id: u32, message in message(), split in any::<prop::sample::Index>()) {
+        let frame = Frame { id, message };
+        let mut buf = Vec::new();
+        frame.encode(&mut buf).unwrap();
+
+        let decoded = Frame::decode(&mut &buf[..], &Limits::default()).unwrap().unwrap();
+        prop_assert_eq!(&decoded, &frame);
+
+        // The incremental decoder gives the same result however the bytes are
+        // split up when they arrive.
+        let split = split.index(buf.len() + 1);
+        let mut decoder = FrameDecoder::new(Limits::default());
+        decoder.extend(&buf[..split]);
+        let early = decoder.next_frame();
+        decoder.extend(&buf[split..]);
+        let decoded = match early {
+            Some(result) => result,
+            None => decoder.next_frame().unwrap(),
+        };
+        prop_assert_eq!(decoded.unwrap(), frame);
+        prop_assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn decoding_arbitrary_bytes_does_not_panic(bytes: Vec<u8>) {
+        let limits = Limits { max_frame: 256, max_field: 64 };
+        let mut stream = &bytes[..];
+        while let Ok(result) = Frame::decode(&mut stream, &limits) {
+            if let Ok(frame) = result {
+                // Anything accepted must survive another round trip.
+                let mut buf = Vec::new();
+                frame.encode(&mut buf).unwrap();
+                let again = Frame::decode(&mut &buf[..], &limits).unwrap().unwrap();
+                prop_assert_eq!(again, frame);
+            }
+        }
+    }
+}
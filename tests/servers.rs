@@ -0,0 +1,768 @@
+//! Behavior tests run against both chat servers, each in a child process
+//! serving on a Unix domain socket.
+//!
+//! The SQLite server keeps its database in the working directory, so several
+//! of its processes can be started in one directory to share it.
+
+use std::{
+    fs,
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use cs262::wire::{
+    client_handshake, Attachment, Client, DeleteMode, Frame, Message, MessageState, Presence,
+    Receipt, Reply, MAX_CHUNK,
+};
+
+/// A scratch directory, removed when dropped.
+struct Dir(PathBuf);
+
+impl Dir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("cs262-{}-{name}", std::process::id()));
+        _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A server process, killed when dropped.
+struct Server {
+    child: Child,
+    socket: PathBuf,
+}
+
+impl Server {
+    /// Start a `wire` or `wire2` server in a directory, with a socket named
+    /// after its index.
+    fn start(kind: &str, dir: &Path, index: usize) -> Self {
+        Self::start_with_args(kind, dir, index, &[])
+    }
+
+    fn start_with_args(kind: &str, dir: &Path, index: usize, args: &[&str]) -> Self {
+        let socket = dir.join(format!("{index}.sock"));
+        let child = Command::new(env!("CARGO_BIN_EXE_cs262"))
+            .args([kind, "server", "--unix"])
+            .arg(&socket)
+            .args(args)
+            .current_dir(dir)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Self { child, socket };
+        while Client::connect_unix(&server.socket).is_err() {
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    fn connect(&self) -> Client {
+        Client::connect_unix(&self.socket).unwrap()
+    }
+
+    fn create(&self, name: &str) {
+        ok(&self.connect(), Message::Create(name.into(), "pw".into()));
+    }
+
+    /// Create an account, and return a connection logged in to it.
+    fn user(&self, name: &str) -> Client {
+        self.create(name);
+        self.login(name)
+    }
+
+    fn login(&self, name: &str) -> Client {
+        let client = self.connect();
+        ok(&client, Message::Login(name.into(), "pw".into()));
+        client
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+/// Run a test against a fresh instance of each server.
+fn each_server(name: &str, test: impl Fn(&Server)) {
+    for kind in ["wire", "wire2"] {
+        let dir = Dir::new(&format!("{name}-{kind}"));
+        let server = Server::start(kind, &dir.0, 0);
+        test(&server);
+    }
+}
+
+fn ok(client: &Client, message: Message) -> Reply {
+    match client.request(message.clone()).unwrap() {
+        Message::Response(Ok(reply)) => reply,
+        response => panic!("unexpected response to {message:?}: {response:?}"),
+    }
+}
+
+fn err(client: &Client, message: Message) -> String {
+    match client.request(message.clone()).unwrap() {
+        Message::Response(Err(err)) => err,
+        response => panic!("unexpected response to {message:?}: {response:?}"),
+    }
+}
+
+#[test]
+fn authentication() {
+    each_server("auth", |server| {
+        let alice = server.user("alice");
+        server.create("bob");
+
+        let client = server.connect();
+        let login = Message::Login("alice".into(), "wrong".into());
+        assert_eq!(err(&client, login), "incorrect password");
+        let deliver = |name: &str| Message::Deliver(name.into(), 0, 10);
+        assert_eq!(err(&client, deliver("alice")), "not logged in");
+        assert_eq!(err(&alice, deliver("bob")), "permission denied");
+        assert_eq!(err(&alice, deliver("nobody")), "permission denied");
+
+        // A session token can be resumed on another connection, until logout.
+        let Reply::Token(token) = ok(&client, Message::Login("alice".into(), "pw".into())) else {
+            panic!("expected token");
+        };
+        ok(&alice, Message::Resume(token.clone()));
+        ok(&alice, deliver("alice"));
+        ok(&alice, Message::Logout);
+        assert_eq!(err(&alice, deliver("alice")), "not logged in");
+        let resume = Message::Resume(token);
+        assert_eq!(err(&server.connect(), resume), "invalid session token");
+    });
+}
+
+#[test]
+fn half_closed_connection_gets_responses() {
+    each_server("half-close", |server| {
+        let mut stream = UnixStream::connect(&server.socket).unwrap();
+        client_handshake(&mut stream).unwrap();
+        // Hashing passwords is slow, so these are still running at shutdown.
+        for id in 1..=8 {
+            let message = Message::Create(format!("user{id}"), "pw".into());
+            Frame { id, message }.encode(&mut stream).unwrap();
+        }
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut ids = Vec::new();
+        while let Ok(frame) = Frame::decode(&mut stream, &Default::default()) {
+            let frame = frame.unwrap();
+            assert_eq!(frame.message, Message::Response(Ok(Reply::Ack)));
+            ids.push(frame.id);
+        }
+        ids.sort();
+        assert_eq!(ids, (1..=8).collect::<Vec<_>>());
+    });
+}
+
+/// IDs of the messages in a reply.
+fn ids(reply: Reply) -> Vec<u64> {
+    match reply {
+        Reply::Messages(messages) => messages.iter().map(|message| message.id).collect(),
+        reply => panic!("expected messages, got {reply:?}"),
+    }
+}
+
+#[test]
+fn paging() {
+    each_server("paging", |server| {
+        let client = server.connect();
+        for name in ["a", "b", "c", "d", "e"] {
+            ok(&client, Message::Create(name.into(), "pw".into()));
+        }
+        let names =
+            |after: &str, limit| match ok(&client, Message::List("".into(), after.into(), limit)) {
+                Reply::Presence(accounts) => {
+                    accounts.into_iter().map(|p| p.name).collect::<Vec<_>>()
+                }
+                reply => panic!("expected accounts, got {reply:?}"),
+            };
+        assert_eq!(names("", 2), ["a", "b"]);
+        assert_eq!(names("b", 2), ["c", "d"]);
+        assert_eq!(names("d", 2), ["e"]);
+
+        let alice = server.login("a");
+        let bob = server.login("b");
+        for i in 0..5 {
+            ok(&alice, Message::Send("b".into(), format!("{i}")));
+        }
+        let all = ids(ok(&bob, Message::Deliver("b".into(), 0, 10)));
+        assert_eq!(all.len(), 5);
+        let first = ids(ok(&bob, Message::Deliver("b".into(), 0, 2)));
+        assert_eq!(first, all[..2]);
+        let rest = ids(ok(&bob, Message::Deliver("b".into(), first[1], 10)));
+        assert_eq!(rest, all[2..]);
+    });
+}
+
+#[test]
+fn groups() {
+    each_server("groups", |server| {
+        let alice = server.user("alice");
+        let bob = server.user("bob");
+        let carol = server.user("carol");
+
+        ok(&alice, Message::CreateGroup("g".into()));
+        let create = Message::CreateGroup("g".into());
+        assert_eq!(err(&bob, create), "group already exists");
+        ok(&bob, Message::JoinGroup("g".into()));
+        assert_eq!(
+            err(&bob, Message::JoinGroup("g".into())),
+            "already a member of group"
+        );
+        let members = ok(&carol, Message::Members("g".into()));
+        assert_eq!(members, Reply::Accounts(vec!["alice".into(), "bob".into()]));
+
+        // Only members can send, and every other member gets a copy.
+        let send = |text: &str| Message::SendGroup("g".into(), text.into());
+        assert_eq!(err(&carol, send("hi")), "not a member of group");
+        ok(&bob, send("hi"));
+        let Reply::Messages(messages) = ok(&alice, Message::Deliver("alice".into(), 0, 10)) else {
+            panic!("expected messages");
+        };
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].sender, "bob");
+        assert_eq!(messages[0].group.as_deref(), Some("g"));
+        assert_eq!(messages[0].text, "hi");
+        assert!(ids(ok(&bob, Message::Deliver("bob".into(), 0, 10))).is_empty());
+
+        // The group is deleted when its last member leaves.
+        ok(&alice, Message::LeaveGroup("g".into()));
+        let leave = Message::LeaveGroup("g".into());
+        assert_eq!(err(&alice, leave), "not a member of group");
+        ok(&bob, Message::LeaveGroup("g".into()));
+        let members = Message::Members("g".into());
+        assert_eq!(err(&carol, members), "group does not exist");
+    });
+}
+
+#[test]
+fn broadcast() {
+    each_server("broadcast", |server| {
+        let alice = server.user("alice");
+        let bob = server.user("bob");
+        server.create("bart");
+        server.create("carol");
+
+        let broadcast = |pattern: &str| Message::Broadcast(pattern.into(), "hi".into());
+        assert_eq!(err(&alice, broadcast("")), "invalid pattern");
+        let recipients = ok(&alice, broadcast("b*"));
+        assert_eq!(
+            recipients,
+            Reply::Accounts(vec!["bart".into(), "bob".into()])
+        );
+        // The sender is never one of the recipients.
+        let recipients = ok(&alice, broadcast("*"));
+        let everyone = ["bart", "bob", "carol"].map(String::from).to_vec();
+        assert_eq!(recipients, Reply::Accounts(everyone));
+        assert_eq!(
+            ids(ok(&bob, Message::Deliver("bob".into(), 0, 10))).len(),
+            2
+        );
+        assert!(ids(ok(&alice, Message::Deliver("alice".into(), 0, 10))).is_empty());
+    });
+}
+
+/// Wait for the next message pushed to a client.
+fn next_push(client: &Client) -> Message {
+    client
+        .pushes()
+        .recv_timeout(Duration::from_secs(5))
+        .expect("no message was pushed")
+}
+
+#[test]
+fn receipts() {
+    each_server("receipts", |server| {
+        let alice = server.user("alice");
+        server.create("bob");
+        ok(&alice, Message::Send("bob".into(), "hi".into()));
+        let sent = |state| {
+            let Reply::Receipts(receipts) = ok(&alice, Message::Sent(0, 10)) else {
+                panic!("expected receipts");
+            };
+            assert_eq!(receipts.len(), 1);
+            assert_eq!(receipts[0].recipient, "bob");
+            assert_eq!(receipts[0].state, state);
+            receipts[0].id
+        };
+        let id = sent(MessageState::Queued);
+
+        let bob = server.login("bob");
+        ok(&bob, Message::Deliver("bob".into(), 0, 10));
+        sent(MessageState::Delivered);
+        ok(&bob, Message::Ack(vec![id]));
+        sent(MessageState::Read);
+        let receipt = Receipt {
+            id,
+            recipient: "bob".into(),
+            state: MessageState::Read,
+        };
+        assert_eq!(next_push(&alice), Message::Receipt(receipt));
+    });
+}
+
+#[test]
+fn unknown_receipt_state() {
+    let dir = Dir::new("receipt-state");
+    let server = Server::start("wire2", &dir.0, 0);
+    let alice = server.user("alice");
+    server.create("bob");
+    ok(&alice, Message::Send("bob".into(), "hi".into()));
+    let conn = rusqlite::Connection::open(dir.0.join("chat.sqlite")).unwrap();
+    conn.execute("UPDATE receipts SET state = 7", []).unwrap();
+    let sent = err(&alice, Message::Sent(0, 10));
+    assert!(sent.contains("invalid message state 7"), "{sent}");
+}
+
+#[test]
+fn blocks() {
+    each_server("blocks", |server| {
+        let alice = server.user("alice");
+        let bob = server.user("bob");
+        let block = Message::Block("alice".into());
+        assert_eq!(
+            err(&bob, Message::Block("nobody".into())),
+            "account does not exist"
+        );
+        ok(&bob, block.clone());
+        assert_eq!(err(&bob, block), "account already blocked");
+
+        // Blocked senders are refused directly, and skipped by fan-out sends.
+        let send = Message::Send("bob".into(), "hi".into());
+        assert_eq!(err(&alice, send.clone()), "blocked by recipient");
+        ok(&bob, Message::CreateGroup("g".into()));
+        ok(&alice, Message::JoinGroup("g".into()));
+        ok(&alice, Message::SendGroup("g".into(), "hi".into()));
+        let recipients = ok(&alice, Message::Broadcast("*".into(), "hi".into()));
+        assert_eq!(recipients, Reply::Accounts(vec![]));
+        assert!(ids(ok(&bob, Message::Deliver("bob".into(), 0, 10))).is_empty());
+
+        ok(&bob, Message::Unblock("alice".into()));
+        let unblock = Message::Unblock("alice".into());
+        assert_eq!(err(&bob, unblock), "account not blocked");
+        ok(&alice, send);
+        assert_eq!(
+            ids(ok(&bob, Message::Deliver("bob".into(), 0, 10))).len(),
+            1
+        );
+    });
+}
+
+#[test]
+fn unsend_and_edit() {
+    each_server("unsend", |server| {
+        let alice = server.user("alice");
+        server.create("bob");
+        let send = |text: &str| {
+            ok(&alice, Message::Send("bob".into(), text.into()));
+            let Reply::Receipts(receipts) = ok(&alice, Message::Sent(0, 100)) else {
+                panic!("expected receipts");
+            };
+            receipts.last().unwrap().id
+        };
+
+        // Queued messages can be changed or taken back.
+        let first = send("helo");
+        let second = send("oops");
+        ok(&alice, Message::Edit(first, "hello".into()));
+        ok(&alice, Message::Unsend(second));
+        assert_eq!(
+            err(&alice, Message::Unsend(second)),
+            "message does not exist"
+        );
+        let bob = server.login("bob");
+        let Reply::Messages(messages) = ok(&bob, Message::Deliver("bob".into(), 0, 10)) else {
+            panic!("expected messages");
+        };
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].text, "hello");
+        let edit = Message::Edit(first, "bye".into());
+        assert_eq!(err(&alice, edit), "message already delivered");
+
+        // Once a message has been pushed, it can't be changed, even right
+        // after it was sent.
+        for i in 0..20 {
+            let id = send(&format!("{i}"));
+            let Message::Push(message) = next_push(&bob) else {
+                panic!("expected message");
+            };
+            assert_eq!(message.id, id);
+            let edit = Message::Edit(id, "changed".into());
+            assert_eq!(err(&alice, edit), "message already delivered");
+            let unsend = Message::Unsend(id);
+            assert_eq!(err(&alice, unsend), "message already delivered");
+        }
+    });
+}
+
+/// Receipts for the messages sent by a client.
+fn sent(client: &Client) -> Vec<Receipt> {
+    match ok(client, Message::Sent(0, 100)) {
+        Reply::Receipts(receipts) => receipts,
+        reply => panic!("expected receipts, got {reply:?}"),
+    }
+}
+
+#[test]
+fn delete_modes() {
+    each_server("delete", |server| {
+        let alice = server.user("alice");
+        let delete = |name: &str, mode| Message::Delete(name.into(), mode);
+
+        // By default, accounts with queued messages can't be deleted.
+        server.create("bob");
+        ok(&alice, Message::Send("bob".into(), "hi".into()));
+        let bob = server.login("bob");
+        let restrict = delete("bob", DeleteMode::Restrict);
+        assert_eq!(err(&bob, restrict), "account has messages");
+
+        // Purged messages are gone, along with their receipts.
+        ok(&bob, delete("bob", DeleteMode::Purge));
+        assert_eq!(err(&bob, Message::Sent(0, 10)), "not logged in");
+        assert!(sent(&alice).is_empty());
+        server.create("bob");
+
+        // Bounced messages go back to their sender, who can see why.
+        server.create("carol");
+        ok(&alice, Message::Send("carol".into(), "hi".into()));
+        let carol = server.login("carol");
+        ok(&carol, delete("carol", DeleteMode::Bounce));
+        let Reply::Messages(messages) = ok(&alice, Message::Deliver("alice".into(), 0, 10)) else {
+            panic!("expected messages");
+        };
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            (messages[0].sender.as_str(), messages[0].text.as_str()),
+            ("alice", "hi")
+        );
+        let receipt = Receipt {
+            id: messages[0].id,
+            recipient: "carol".into(),
+            state: MessageState::Bounced,
+        };
+        assert_eq!(sent(&alice), std::slice::from_ref(&receipt));
+        ok(&alice, Message::Ack(vec![receipt.id]));
+        assert_eq!(sent(&alice), [receipt]);
+        server.create("carol");
+
+        // Tombstoned names can never be used again.
+        let dave = server.user("dave");
+        ok(&dave, delete("dave", DeleteMode::Tombstone));
+        let create = Message::Create("dave".into(), "pw".into());
+        assert_eq!(err(&alice, create), "account already exists");
+        let rename = Message::Rename("alice".into(), "dave".into());
+        assert_eq!(err(&alice, rename), "account already exists");
+    });
+}
+
+#[test]
+fn bounce_to_reused_name() {
+    each_server("bounce-reused", |server| {
+        let bob = server.user("bob");
+        server.create("carol");
+        ok(&bob, Message::Send("carol".into(), "old bob secret".into()));
+        ok(&bob, Message::Delete("bob".into(), DeleteMode::Purge));
+
+        // The old account's message has nowhere to go back to, so it is
+        // dropped rather than handed to the new owner of the name.
+        let bob = server.user("bob");
+        let carol = server.login("carol");
+        ok(&carol, Message::Delete("carol".into(), DeleteMode::Bounce));
+        assert!(messages(&bob, "bob").is_empty());
+        assert!(sent(&bob).is_empty());
+    });
+}
+
+#[test]
+fn delete_on_another_process() {
+    let dir = Dir::new("delete-processes");
+    let first = Server::start("wire2", &dir.0, 0);
+    let second = Server::start("wire2", &dir.0, 1);
+    let stale = first.user("bob");
+    let bob = second.login("bob");
+    ok(&bob, Message::Delete("bob".into(), DeleteMode::Purge));
+
+    // A new account with the same name is none of the old session's business.
+    second.create("bob");
+    let alice = second.user("alice");
+    ok(&alice, Message::Send("bob".into(), "secret".into()));
+    let pushed = stale.pushes().recv_timeout(Duration::from_millis(500));
+    assert!(pushed.is_err(), "pushed to deleted account: {pushed:?}");
+    let deliver = Message::Deliver("bob".into(), 0, 10);
+    assert_eq!(err(&stale, deliver), "not logged in");
+    let send = Message::Send("alice".into(), "hi".into());
+    assert_eq!(err(&stale, send), "not logged in");
+}
+
+/// Messages queued for a logged-in user.
+fn messages(client: &Client, name: &str) -> Vec<(String, String)> {
+    match ok(client, Message::Deliver(name.into(), 0, 100)) {
+        Reply::Messages(messages) => messages
+            .into_iter()
+            .map(|message| (message.sender, message.text))
+            .collect(),
+        reply => panic!("expected messages, got {reply:?}"),
+    }
+}
+
+fn pair(sender: &str, text: &str) -> (String, String) {
+    (sender.into(), text.into())
+}
+
+#[test]
+fn rename() {
+    each_server("rename", |server| {
+        let alice = server.user("alice");
+        let bob = server.user("bob");
+        let carol = server.user("carol");
+        ok(&alice, Message::Send("bob".into(), "to bob".into()));
+        ok(&bob, Message::Send("alice".into(), "from bob".into()));
+        ok(&bob, Message::Block("carol".into()));
+        ok(&bob, Message::CreateGroup("g".into()));
+
+        let rename = |name: &str| Message::Rename("bob".into(), name.into());
+        assert_eq!(err(&bob, rename("alice")), "account already exists");
+        ok(&bob, rename("robert"));
+
+        // Messages, receipts, block lists and groups follow the account.
+        assert_eq!(messages(&bob, "robert"), [pair("alice", "to bob")]);
+        let deliver = Message::Deliver("bob".into(), 0, 10);
+        assert_eq!(err(&bob, deliver), "permission denied");
+        assert_eq!(messages(&alice, "alice"), [pair("robert", "from bob")]);
+        assert_eq!(sent(&alice)[0].recipient, "robert");
+        let send = Message::Send("robert".into(), "hi".into());
+        assert_eq!(err(&carol, send), "blocked by recipient");
+        let members = ok(&carol, Message::Members("g".into()));
+        assert_eq!(members, Reply::Accounts(vec!["robert".into()]));
+
+        // The old name is free, and the session doesn't follow it.
+        server.create("bob");
+        ok(&alice, Message::Send("bob".into(), "to new bob".into()));
+        assert_eq!(messages(&server.login("bob"), "bob").len(), 1);
+        assert_eq!(messages(&bob, "robert").len(), 1);
+
+        // Messages left by a deleted account keep its name when a later
+        // account with that name is renamed.
+        let old = server.login("bob");
+        ok(&old, Message::Send("carol".into(), "old".into()));
+        ok(&old, Message::Delete("bob".into(), DeleteMode::Purge));
+        let new = server.user("bob");
+        ok(&new, Message::Send("carol".into(), "new".into()));
+        ok(&new, Message::Rename("bob".into(), "bobby".into()));
+        let expected = [pair("bob", "old"), pair("bobby", "new")];
+        assert_eq!(messages(&carol, "carol"), expected);
+    });
+}
+
+#[test]
+fn rename_on_another_process() {
+    let dir = Dir::new("rename-processes");
+    let first = Server::start("wire2", &dir.0, 0);
+    let second = Server::start("wire2", &dir.0, 1);
+    let stale = first.user("bob");
+    let bob = second.login("bob");
+    ok(&bob, Message::Rename("bob".into(), "robert".into()));
+
+    // The old session stays with the account under its new name, and can't
+    // read the mail of a new account that takes the old one.
+    second.create("bob");
+    let alice = second.user("alice");
+    ok(&alice, Message::Send("bob".into(), "secret".into()));
+    let pushed = stale.pushes().recv_timeout(Duration::from_millis(500));
+    assert!(pushed.is_err(), "pushed to renamed account: {pushed:?}");
+    let deliver = Message::Deliver("bob".into(), 0, 10);
+    assert_eq!(err(&stale, deliver), "permission denied");
+    assert!(messages(&stale, "robert").is_empty());
+    ok(&stale, Message::Send("alice".into(), "hi".into()));
+    assert_eq!(messages(&alice, "alice"), [pair("robert", "hi")]);
+
+    // Renaming the new account on one process leaves the messages of a deleted
+    // account with that name alone on the other.
+    second.create("carol");
+    let old = second.login("bob");
+    ok(&old, Message::Send("carol".into(), "old".into()));
+    ok(&old, Message::Delete("bob".into(), DeleteMode::Purge));
+    let new = first.user("bob");
+    ok(&new, Message::Send("carol".into(), "new".into()));
+    ok(&new, Message::Rename("bob".into(), "bobby".into()));
+    let carol = second.login("carol");
+    let expected = [pair("bob", "old"), pair("bobby", "new")];
+    assert_eq!(messages(&carol, "carol"), expected);
+}
+
+/// Wait until the presence of an account satisfies a condition, since the
+/// SQLite server records it in the background.
+fn wait_for_presence(client: &Client, name: &str, done: impl Fn(&Presence) -> bool) -> Presence {
+    let start = Instant::now();
+    loop {
+        let Reply::Presence(accounts) = ok(client, Message::List(name.into(), "".into(), 1)) else {
+            panic!("expected accounts");
+        };
+        if done(&accounts[0]) {
+            return accounts[0].clone();
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "{:?}",
+            accounts[0]
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn presence() {
+    each_server("presence", |server| {
+        let client = server.connect();
+        server.create("alice");
+        let alice = wait_for_presence(&client, "alice", |_| true);
+        assert!(!alice.online);
+        assert_eq!(alice.last_seen, 0);
+
+        let session = server.login("alice");
+        wait_for_presence(&client, "alice", |p| p.online);
+        ok(&session, Message::Logout);
+        let alice = wait_for_presence(&client, "alice", |p| !p.online);
+        assert!(alice.last_seen > 0);
+
+        // Closing the connection also counts as leaving.
+        let mut stream = UnixStream::connect(&server.socket).unwrap();
+        client_handshake(&mut stream).unwrap();
+        let message = Message::Login("alice".into(), "pw".into());
+        Frame { id: 1, message }.encode(&mut stream).unwrap();
+        Frame::decode(&mut stream, &Default::default())
+            .unwrap()
+            .unwrap();
+        wait_for_presence(&client, "alice", |p| p.online);
+        drop(stream);
+        let last_seen = alice.last_seen;
+        wait_for_presence(&client, "alice", |p| !p.online && p.last_seen > last_seen);
+    });
+}
+
+#[test]
+fn presence_on_another_process() {
+    let dir = Dir::new("presence-processes");
+    let first = Server::start("wire2", &dir.0, 0);
+    let second = Server::start("wire2", &dir.0, 1);
+    let alice = first.user("alice");
+    let client = second.connect();
+    wait_for_presence(&client, "alice", |p| p.online);
+    ok(&alice, Message::Logout);
+    let presence = wait_for_presence(&client, "alice", |p| !p.online);
+    assert!(presence.last_seen > 0);
+}
+
+#[test]
+fn attachments() {
+    each_server("attachments", |server| {
+        let alice = server.user("alice");
+        let bob = server.user("bob");
+        server.create("carol");
+        ok(&server.login("carol"), Message::Block("alice".into()));
+
+        // Chunks must be uploaded in order, before the file is sent.
+        let send = |name: &str| Message::SendFile(name.into(), "a.bin".into(), "x/y".into());
+        assert_eq!(err(&alice, send("bob")), "no attachment uploaded");
+        let upload = Message::Upload(1, vec![0]);
+        assert_eq!(err(&alice, upload), "chunk out of order");
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        for (i, chunk) in data.chunks(MAX_CHUNK as usize).enumerate() {
+            let offset = (i * MAX_CHUNK as usize) as u64;
+            ok(&alice, Message::Upload(offset, chunk.to_vec()));
+        }
+
+        // The upload is kept when a send fails, and used up when it succeeds.
+        assert_eq!(err(&alice, send("nobody")), "account does not exist");
+        assert_eq!(err(&alice, send("carol")), "blocked by recipient");
+        ok(&alice, send("bob"));
+        assert_eq!(err(&alice, send("bob")), "no attachment uploaded");
+
+        let Reply::Messages(messages) = ok(&bob, Message::Deliver("bob".into(), 0, 10)) else {
+            panic!("expected messages");
+        };
+        let id = messages[0].id;
+        let attachment = Attachment {
+            filename: "a.bin".into(),
+            mime: "x/y".into(),
+            size: data.len() as u64,
+        };
+        assert_eq!(messages[0].attachment, Some(attachment));
+
+        // Only the recipient can download it, and only until it is acknowledged.
+        let download = Message::Download(id, 0, u32::MAX);
+        assert_eq!(err(&alice, download.clone()), "attachment does not exist");
+        let mut downloaded = Vec::new();
+        while downloaded.len() < data.len() {
+            let offset = downloaded.len() as u64;
+            let Reply::Data(chunk) = ok(&bob, Message::Download(id, offset, u32::MAX)) else {
+                panic!("expected data");
+            };
+            assert!(!chunk.is_empty() && chunk.len() <= MAX_CHUNK as usize);
+            downloaded.extend(chunk);
+        }
+        assert_eq!(downloaded, data);
+        let Reply::Data(chunk) = ok(&bob, Message::Download(id, 1000, 10)) else {
+            panic!("expected data");
+        };
+        assert_eq!(chunk, data[1000..1010]);
+        ok(&bob, Message::Ack(vec![id]));
+        assert_eq!(err(&bob, download), "attachment does not exist");
+    });
+}
+
+#[test]
+fn upload_space_is_shared() {
+    for kind in ["wire", "wire2"] {
+        let dir = Dir::new(&format!("upload-space-{kind}"));
+        let space = (3 * MAX_CHUNK).to_string();
+        let server = Server::start_with_args(kind, &dir.0, 0, &["--max-upload-space", &space]);
+        let alice = server.user("alice");
+        server.create("bob");
+        let chunk = vec![0; MAX_CHUNK as usize];
+        let upload = |i: u32| Message::Upload((i * MAX_CHUNK) as u64, chunk.clone());
+
+        // Another connection holds two chunks of the space, until it closes.
+        let mut stream = UnixStream::connect(&server.socket).unwrap();
+        client_handshake(&mut stream).unwrap();
+        let login = Message::Login("bob".into(), "pw".into());
+        for (id, message) in [(1, login), (2, upload(0)), (3, upload(1))] {
+            Frame { id, message }.encode(&mut stream).unwrap();
+            let frame = Frame::decode(&mut stream, &Default::default()).unwrap();
+            assert!(matches!(frame.unwrap().message, Message::Response(Ok(_))));
+        }
+        ok(&alice, upload(0));
+        assert_eq!(err(&alice, upload(1)), "server is out of upload space");
+        assert_eq!(err(&alice, upload(1)), "chunk out of order");
+        drop(stream);
+        let start = Instant::now();
+        loop {
+            ok(&alice, upload(0));
+            if alice.request(upload(1)).unwrap() == Message::Response(Ok(Reply::Ack)) {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "space not freed");
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        // Sending the file frees its space for the next upload.
+        let send = Message::SendFile("bob".into(), "a.bin".into(), "x/y".into());
+        ok(&alice, send);
+        for i in 0..3 {
+            ok(&alice, upload(i));
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Tests of the TLS transport against an in-process server.
+
+use std::{fs, net::TcpStream, path::PathBuf, thread, time::Duration};
+
+use cs262::wire::{self, tls, Client, Message, Reply, ServerConfig};
+
+const PORT: u16 = 15724;
+
+fn cert_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cs262-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn pinned_certificate() {
+    let dir = cert_dir();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    let (other_cert, other_key) = (dir.join("other.pem"), dir.join("other.key"));
+    tls::generate_cert(&cert, &key).unwrap();
+    tls::generate_cert(&other_cert, &other_key).unwrap();
+
+    let config = ServerConfig {
+        port: PORT,
+        tls: Some(tls::server_config(&cert, &key).unwrap()),
+        ..Default::default()
+    };
+    thread::spawn(move || wire::run_server(&config).unwrap());
+    while TcpStream::connect(("127.0.0.1", PORT)).is_err() {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let client =
+        Client::connect_tls(("127.0.0.1", PORT), tls::client_config(&cert).unwrap()).unwrap();
+    let response = client
+        .request(Message::List("".into(), "".into(), 100))
+        .unwrap();
+    assert_eq!(response, Message::Response(Ok(Reply::Presence(vec![]))));
+
+    // Certificates other than the pinned one are not trusted.
+    let other = tls::client_config(&other_cert).unwrap();
+    assert!(Client::connect_tls(("127.0.0.1", PORT), other).is_err());
+
+    // Neither are clients that don't speak TLS.
+    assert!(Client::connect(("127.0.0.1", PORT)).is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,48 @@
+//! Tests of the Unix domain socket transport against an in-process server.
+
+use std::{fs, thread, time::Duration};
+
+use cs262::wire::{self, Client, Message, Presence, Reply, ServerConfig};
+
+#[test]
+fn unix_socket() {
+    let path = std::env::temp_dir().join(format!("cs262-{}.sock", std::process::id()));
+
+    // A socket file left behind by a dead server is replaced.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let config = ServerConfig {
+        unix: Some(path.clone()),
+        ..Default::default()
+    };
+    thread::spawn(move || wire::run_server(&config).unwrap());
+    let client = loop {
+        match Client::connect_unix(&path) {
+            Ok(client) => break client,
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    };
+
+    let response = client.request(Message::Create("alice".into(), "pw".into()));
+    assert_eq!(response.unwrap(), Message::Response(Ok(Reply::Ack)));
+    let response = client
+        .request(Message::List("".into(), "".into(), 100))
+        .unwrap();
+    assert_eq!(
+        response,
+        Message::Response(Ok(Reply::Presence(vec![Presence {
+            name: "alice".into(),
+            online: false,
+            last_seen: 0,
+        }])))
+    );
+
+    // A socket that is still being served is not.
+    let config = ServerConfig {
+        unix: Some(path.clone()),
+        ..Default::default()
+    };
+    assert!(wire::run_server(&config).is_err());
+
+    fs::remove_file(path).unwrap();
+}
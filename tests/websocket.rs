@@ -0,0 +1,144 @@
+//! Tests of the WebSocket listener against an in-process server.
+
+use std::{net::TcpStream, thread, time::Duration};
+
+use cs262::wire::{
+    self, ChatMessage, Client, Frame, Limits, Message, Reply, ServerConfig, CAP_PUSH,
+    PROTOCOL_VERSION,
+};
+use tungstenite::{protocol::frame::coding::CloseCode, WebSocket};
+
+const PORT: u16 = 15726;
+const WEBSOCKET_PORT: u16 = 15727;
+
+type Socket = WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>;
+
+fn connect() -> Socket {
+    let url = format!("ws://127.0.0.1:{WEBSOCKET_PORT}/");
+    tungstenite::connect(url).unwrap().0
+}
+
+fn send(socket: &mut Socket, message: &Message) {
+    let mut buf = Vec::new();
+    message.encode(&mut buf).unwrap();
+    socket.send(tungstenite::Message::Binary(buf)).unwrap();
+}
+
+fn request(socket: &mut Socket, frame: Frame) {
+    let mut buf = Vec::new();
+    frame.encode(&mut buf).unwrap();
+    socket.send(tungstenite::Message::Binary(buf)).unwrap();
+}
+
+fn recv(socket: &mut Socket) -> Vec<u8> {
+    match socket.read().unwrap() {
+        tungstenite::Message::Binary(data) => data,
+        message => panic!("unexpected message {message:?}"),
+    }
+}
+
+fn recv_frame(socket: &mut Socket) -> Frame {
+    let data = recv(socket);
+    Frame::decode(&mut &data[..], &Limits::default())
+        .unwrap()
+        .unwrap()
+}
+
+#[test]
+fn binary_frames_and_pushes() {
+    let config = ServerConfig {
+        port: PORT,
+        websocket_port: Some(WEBSOCKET_PORT),
+        ..Default::default()
+    };
+    thread::spawn(move || wire::run_server(&config).unwrap());
+    while TcpStream::connect(("127.0.0.1", WEBSOCKET_PORT)).is_err() {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let mut socket = connect();
+    send(&mut socket, &Message::Hello(PROTOCOL_VERSION, CAP_PUSH));
+    let welcome = Message::decode(&mut &recv(&mut socket)[..], &Limits::default()).unwrap();
+    assert_eq!(welcome, Message::Welcome(PROTOCOL_VERSION, CAP_PUSH));
+
+    // Frames may be split across messages.
+    let mut buf = Vec::new();
+    let create = Message::Create("alice".into(), "pw".into());
+    Frame {
+        id: 1,
+        message: create,
+    }
+    .encode(&mut buf)
+    .unwrap();
+    let (first, second) = buf.split_at(5);
+    socket
+        .send(tungstenite::Message::Binary(first.to_vec()))
+        .unwrap();
+    socket
+        .send(tungstenite::Message::Binary(second.to_vec()))
+        .unwrap();
+    let frame = recv_frame(&mut socket);
+    assert_eq!(frame.id, 1);
+    assert_eq!(frame.message, Message::Response(Ok(Reply::Ack)));
+
+    let login = Message::Login("alice".into(), "pw".into());
+    request(
+        &mut socket,
+        Frame {
+            id: 2,
+            message: login,
+        },
+    );
+    let frame = recv_frame(&mut socket);
+    assert_eq!(frame.id, 2);
+    assert!(matches!(
+        frame.message,
+        Message::Response(Ok(Reply::Token(_)))
+    ));
+
+    // Messages from a TCP client are pushed to the browser.
+    let client = Client::connect(("127.0.0.1", PORT)).unwrap();
+    client
+        .request(Message::Create("bob".into(), "pw".into()))
+        .unwrap();
+    client
+        .request(Message::Login("bob".into(), "pw".into()))
+        .unwrap();
+    let sent = client.request(Message::Send("alice".into(), "hi".into()));
+    assert_eq!(sent.unwrap(), Message::Response(Ok(Reply::Ack)));
+    let frame = recv_frame(&mut socket);
+    assert_eq!(frame.id, 0);
+    let Message::Push(ChatMessage { sender, text, .. }) = frame.message else {
+        panic!("expected a push, got {:?}", frame.message);
+    };
+    assert_eq!((sender.as_str(), text.as_str()), ("bob", "hi"));
+
+    request(
+        &mut socket,
+        Frame {
+            id: 3,
+            message: Message::List("".into(), "".into(), 100),
+        },
+    );
+    let frame = recv_frame(&mut socket);
+    assert_eq!(frame.id, 3);
+    let Message::Response(Ok(Reply::Presence(accounts))) = frame.message else {
+        panic!("expected accounts, got {:?}", frame.message);
+    };
+    let online: Vec<_> = accounts
+        .iter()
+        .map(|p| (p.name.as_str(), p.online))
+        .collect();
+    assert_eq!(online, [("alice", true), ("bob", true)]);
+
+    // Text messages are refused.
+    socket
+        .send(tungstenite::Message::Text("hello".into()))
+        .unwrap();
+    match socket.read().unwrap() {
+        tungstenite::Message::Close(Some(close)) => {
+            assert_eq!(close.code, CloseCode::Unsupported)
+        }
+        message => panic!("unexpected message {message:?}"),
+    }
+}